@@ -0,0 +1,698 @@
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+use crate::{ParserErr, Range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TypeSize {
+    _8,
+    _16,
+    _32,
+    _64,
+}
+
+impl TypeSize {
+    pub fn from_bits(bits: &str) -> Option<TypeSize> {
+        match bits {
+            "8" => Some(TypeSize::_8),
+            "16" => Some(TypeSize::_16),
+            "32" => Some(TypeSize::_32),
+            "64" => Some(TypeSize::_64),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            TypeSize::_8 => 8,
+            TypeSize::_16 => 16,
+            TypeSize::_32 => 32,
+            TypeSize::_64 => 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type<'a> {
+    /// Signed integer, written `s8`..`s64`
+    I(TypeSize),
+    U(TypeSize),
+    /// Only 32 and 64 bit floats exist
+    F(TypeSize),
+    /// Result of comparisons, written `bool`
+    Bool,
+    Ptr(Box<Type<'a>>),
+    /// Reference to a struct definition, written `$Name`
+    Ref(&'a str),
+}
+
+impl<'a> Type<'a> {
+    /// The struct this type names, looking through pointers
+    pub fn struct_name(&self) -> Option<&'a str> {
+        match self {
+            Type::Ref(name) => Some(name),
+            Type::Ptr(t) => t.struct_name(),
+            _ => None,
+        }
+    }
+
+    /// Pointer to this type, the `*` postfix
+    pub fn ptr(self) -> Type<'a> {
+        Type::Ptr(Box::new(self))
+    }
+
+    /// Width of integers, floats and `bool` (1 bit), `None` for pointers and structs
+    pub fn bits(&self) -> Option<u32> {
+        match self {
+            Type::I(s) | Type::U(s) | Type::F(s) => Some(s.bits()),
+            Type::Bool => Some(1),
+            Type::Ptr(_) | Type::Ref(_) => None,
+        }
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self, Type::I(_) | Type::U(_))
+    }
+
+    /// Parses a primitive type name such as `s32` or `f64`
+    pub fn primitive(name: &str) -> Option<Type<'a>> {
+        if name == "bool" {
+            return Some(Type::Bool);
+        }
+        let size = TypeSize::from_bits(name.get(1..)?)?;
+        match (&name[..1], size) {
+            ("s", size) => Some(Type::I(size)),
+            ("u", size) => Some(Type::U(size)),
+            ("f", size @ (TypeSize::_32 | TypeSize::_64)) => Some(Type::F(size)),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for Type<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Type::I(s) => write!(f, "s{}", s.bits()),
+            Type::U(s) => write!(f, "u{}", s.bits()),
+            Type::F(s) => write!(f, "f{}", s.bits()),
+            Type::Bool => write!(f, "bool"),
+            Type::Ptr(t) => write!(f, "{t}*"),
+            Type::Ref(name) => write!(f, "${name}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AstNode<'a> {
+    Err(ParserErr, Range),
+    Local(&'a str, Range),
+    Global(&'a str, Range),
+    StrRef(&'a str, Range),
+    Const(Type<'a>, &'a str, Range),
+    CPtrOffset(Type<'a>, &'a str, Range),
+    SPtrOffset(Type<'a>, Vec<&'a str>, Range),
+    Cpy(Box<AstNode<'a>>, Range),
+    Add(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Sub(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Div(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Mul(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Rem(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    And(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Or(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Xor(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Shl(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    /// Arithmetic for signed types, logical for unsigned
+    Shr(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    /// Destination type, then source type
+    Cast(CastOp, Type<'a>, Type<'a>, Box<AstNode<'a>>, Range),
+    Neg(Type<'a>, Box<AstNode<'a>>, Range),
+    Not(Type<'a>, Box<AstNode<'a>>, Range),
+    /// Comparisons take operands of the annotated type and produce a `bool`
+    Eq(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Ne(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Lt(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Le(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Gt(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Ge(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    /// Return type is `None` when calling a void function
+    Call(
+        Option<Type<'a>>,
+        Box<AstNode<'a>>,
+        Vec<(Type<'a>, AstNode<'a>)>,
+        Range,
+    ),
+    Ret(Type<'a>, Box<AstNode<'a>>, Range),
+    RetVoid(Range),
+    Stalloc(Type<'a>, Box<AstNode<'a>>, Range),
+    /// Heap counterpart of `Stalloc`, the memory lives until it's freed
+    Halloc(Type<'a>, Box<AstNode<'a>>, Range),
+    Hfree(Box<AstNode<'a>>, Range),
+    /// Resizes a heap allocation to a number of elements, moving it if needed
+    Hrealloc(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Ptroffset(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Load(Type<'a>, Box<AstNode<'a>>, Range),
+    Store(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    Dbg(Type<'a>, Box<AstNode<'a>>, Range),
+    Equals(Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+    /// Starts a basic block, written `.name`
+    Label(&'a str, Range),
+    Jmp(&'a str, Range),
+    /// Jumps to the first label if the operand is non-zero, otherwise to the second
+    Br(Type<'a>, Box<AstNode<'a>>, &'a str, &'a str, Range),
+    Sys(SysOp, Vec<AstNode<'a>>, Range),
+}
+
+/// A named string constant from the `--NIR--` file header
+#[derive(Debug)]
+pub struct HeaderConst<'a> {
+    pub name: &'a str,
+    pub value: String,
+    pub span: Range,
+}
+
+#[derive(Debug)]
+pub struct Param<'a> {
+    pub ty: Type<'a>,
+    pub name: &'a str,
+    pub span: Range,
+}
+
+#[derive(Debug)]
+pub struct Function<'a> {
+    pub name: &'a str,
+    /// `None` for void functions
+    pub ret: Option<Type<'a>>,
+    /// The return type, or the empty brackets of a void function
+    pub ret_span: Range,
+    pub params: Vec<Param<'a>>,
+    pub body: Vec<AstNode<'a>>,
+    pub span: Range,
+}
+
+#[derive(Debug)]
+pub struct Field<'a> {
+    /// `None` for positional fields, which are accessed by their index
+    pub name: Option<&'a str>,
+    pub ty: Type<'a>,
+    pub span: Range,
+}
+
+#[derive(Debug)]
+pub struct StructDef<'a> {
+    pub name: &'a str,
+    pub fields: Vec<Field<'a>>,
+    /// Written `packed struct`, fields have no padding and the struct has an alignment of 1
+    pub packed: bool,
+    pub span: Range,
+}
+
+impl<'a> StructDef<'a> {
+    /// Looks up a field by name, or by index for positional fields
+    pub fn field(&self, name: &str) -> Option<(usize, &Field<'a>)> {
+        if let Some(x) = self
+            .fields
+            .iter()
+            .enumerate()
+            .find(|(_, f)| f.name == Some(name))
+        {
+            return Some(x);
+        }
+        let idx: usize = name.parse().ok()?;
+        self.fields.get(idx).map(|f| (idx, f))
+    }
+}
+
+#[derive(Debug)]
+pub enum Item<'a> {
+    Function(Function<'a>),
+    Struct(StructDef<'a>),
+}
+
+impl Item<'_> {
+    pub fn span(&self) -> Range {
+        match self {
+            Item::Function(f) => f.span.clone(),
+            Item::Struct(s) => s.span.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Module<'a> {
+    pub header: Vec<HeaderConst<'a>>,
+    pub items: Vec<Item<'a>>,
+}
+
+#[derive(Debug)]
+pub enum StructErr<'a> {
+    UnknownStruct(&'a str),
+    /// A path went through a field that is not a struct
+    NotAStruct(Type<'a>),
+    UnknownField {
+        strukt: &'a str,
+        field: &'a str,
+    },
+}
+
+impl core::fmt::Display for StructErr<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StructErr::UnknownStruct(name) => write!(f, "undefined struct `${name}`"),
+            StructErr::NotAStruct(ty) => write!(f, "{ty} is not a struct"),
+            StructErr::UnknownField { strukt, field } => {
+                write!(f, "struct `${strukt}` has no field `{field}`")
+            }
+        }
+    }
+}
+
+/// A struct field reached through a `sptroffset` path
+#[derive(Debug)]
+pub struct FieldPath<'m, 'a> {
+    /// Index of the field taken in each struct along the path
+    pub indices: Vec<usize>,
+    pub ty: &'m Type<'a>,
+}
+
+/// Struct definitions of a module, by name
+#[derive(Debug, Default)]
+pub struct StructTable<'m, 'a> {
+    structs: BTreeMap<&'a str, &'m StructDef<'a>>,
+}
+
+impl<'m, 'a> StructTable<'m, 'a> {
+    /// Duplicate names are left for the resolver to report, the first definition wins
+    pub fn new(module: &'m Module<'a>) -> Self {
+        let mut structs = BTreeMap::new();
+        for item in &module.items {
+            if let Item::Struct(s) = item {
+                structs.entry(s.name).or_insert(s);
+            }
+        }
+        StructTable { structs }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'m StructDef<'a>> {
+        self.structs.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'m StructDef<'a>> + '_ {
+        self.structs.values().copied()
+    }
+
+    /// Resolves a `Type::Ref` to its definition
+    pub fn resolve(&self, ty: &Type<'a>) -> Result<&'m StructDef<'a>, StructErr<'a>> {
+        match ty {
+            Type::Ref(name) => self.get(name).ok_or(StructErr::UnknownStruct(name)),
+            _ => Err(StructErr::NotAStruct(ty.clone())),
+        }
+    }
+
+    /// Walks a `sptroffset` path through (possibly nested) structs starting at `ty`
+    pub fn resolve_path(
+        &self,
+        ty: &'m Type<'a>,
+        path: &[&'a str],
+    ) -> Result<FieldPath<'m, 'a>, StructErr<'a>> {
+        let mut indices = Vec::with_capacity(path.len());
+        let mut ty = ty;
+        for field in path {
+            let s = self.resolve(ty)?;
+            let (idx, f) = s.field(field).ok_or(StructErr::UnknownField {
+                strukt: s.name,
+                field,
+            })?;
+            indices.push(idx);
+            ty = &f.ty;
+        }
+        Ok(FieldPath { indices, ty })
+    }
+}
+
+/// Two operand instructions, see [`AstNode::binop`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Div,
+    Mul,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp {
+    /// Instruction name as written in NIR
+    pub fn name(self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Div => "div",
+            BinOp::Mul => "mul",
+            BinOp::Rem => "rem",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Xor => "xor",
+            BinOp::Shl => "shl",
+            BinOp::Shr => "shr",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Le => "le",
+            BinOp::Gt => "gt",
+            BinOp::Ge => "ge",
+        }
+    }
+
+    /// Comparisons produce a `bool` rather than a value of the operand type
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+impl UnOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnOp::Neg => "neg",
+            UnOp::Not => "not",
+        }
+    }
+}
+
+/// Conversion instructions, written `&y = zext(u64) u8 &x`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CastOp {
+    Zext,
+    Sext,
+    Trunc,
+    /// Float to integer, rounds towards zero and saturates, NaN becomes 0
+    Ftoi,
+    Itof,
+    Fext,
+    Ftrunc,
+    /// Reinterprets the bits of a value of the same width
+    Bitcast,
+    Ptrtoint,
+    Inttoptr,
+}
+
+impl CastOp {
+    pub const ALL: [CastOp; 10] = [
+        CastOp::Zext,
+        CastOp::Sext,
+        CastOp::Trunc,
+        CastOp::Ftoi,
+        CastOp::Itof,
+        CastOp::Fext,
+        CastOp::Ftrunc,
+        CastOp::Bitcast,
+        CastOp::Ptrtoint,
+        CastOp::Inttoptr,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CastOp::Zext => "zext",
+            CastOp::Sext => "sext",
+            CastOp::Trunc => "trunc",
+            CastOp::Ftoi => "ftoi",
+            CastOp::Itof => "itof",
+            CastOp::Fext => "fext",
+            CastOp::Ftrunc => "ftrunc",
+            CastOp::Bitcast => "bitcast",
+            CastOp::Ptrtoint => "ptrtoint",
+            CastOp::Inttoptr => "inttoptr",
+        }
+    }
+
+    /// Whether converting a `from` value to `to` with this instruction is allowed
+    pub fn is_legal(self, from: &Type, to: &Type) -> bool {
+        let wider = || to.bits() > from.bits();
+        match self {
+            CastOp::Zext => (from.is_int() || *from == Type::Bool) && to.is_int() && wider(),
+            CastOp::Sext => from.is_int() && to.is_int() && wider(),
+            CastOp::Trunc => {
+                from.is_int()
+                    && match to {
+                        Type::Bool => !wider(),
+                        _ => to.is_int() && to.bits() < from.bits(),
+                    }
+            }
+            CastOp::Ftoi => matches!(from, Type::F(_)) && to.is_int(),
+            CastOp::Itof => from.is_int() && matches!(to, Type::F(_)),
+            CastOp::Fext => *from == Type::F(TypeSize::_32) && *to == Type::F(TypeSize::_64),
+            CastOp::Ftrunc => *from == Type::F(TypeSize::_64) && *to == Type::F(TypeSize::_32),
+            CastOp::Bitcast => match (from, to) {
+                (Type::Ptr(_), Type::Ptr(_)) => true,
+                (Type::I(_) | Type::U(_) | Type::F(_), Type::I(_) | Type::U(_) | Type::F(_)) => {
+                    from.bits() == to.bits()
+                }
+                _ => false,
+            },
+            CastOp::Ptrtoint => matches!(from, Type::Ptr(_)) && to.is_int(),
+            CastOp::Inttoptr => from.is_int() && matches!(to, Type::Ptr(_)),
+        }
+    }
+}
+
+/// Portable operations on the world outside of the program, written `sys write &fd, &buf, &len`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SysOp {
+    /// Writes `len` bytes from `buf` to `fd`, produces the number written or a negative error
+    Write,
+    /// Reads up to `len` bytes from `fd` into `buf`, produces the number read, 0 at the end or
+    /// a negative error
+    Read,
+    /// Ends the program with an exit code
+    Exit,
+    /// Nanoseconds since an unspecified point in time, never decreases
+    Clock,
+    /// Allocates `size` bytes aligned to `align` on the heap, produces null if it can't
+    Alloc,
+    /// Frees memory from `sys alloc`, freeing null does nothing
+    Free,
+}
+
+impl SysOp {
+    pub const ALL: [SysOp; 6] = [
+        SysOp::Write,
+        SysOp::Read,
+        SysOp::Exit,
+        SysOp::Clock,
+        SysOp::Alloc,
+        SysOp::Free,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SysOp::Write => "write",
+            SysOp::Read => "read",
+            SysOp::Exit => "exit",
+            SysOp::Clock => "clock",
+            SysOp::Alloc => "alloc",
+            SysOp::Free => "free",
+        }
+    }
+
+    /// Types of the operands
+    pub fn params(self) -> Vec<Type<'static>> {
+        let buf = || Type::U(TypeSize::_8).ptr();
+        match self {
+            SysOp::Write | SysOp::Read => {
+                alloc::vec![Type::I(TypeSize::_32), buf(), Type::U(TypeSize::_64)]
+            }
+            SysOp::Exit => alloc::vec![Type::I(TypeSize::_32)],
+            SysOp::Clock => Vec::new(),
+            SysOp::Alloc => alloc::vec![Type::U(TypeSize::_64), Type::U(TypeSize::_64)],
+            SysOp::Free => alloc::vec![buf()],
+        }
+    }
+
+    /// Type of the value produced, `None` if there is none
+    pub fn ret(self) -> Option<Type<'static>> {
+        match self {
+            SysOp::Write | SysOp::Read => Some(Type::I(TypeSize::_64)),
+            SysOp::Clock => Some(Type::U(TypeSize::_64)),
+            SysOp::Alloc => Some(Type::U(TypeSize::_8).ptr()),
+            SysOp::Exit | SysOp::Free => None,
+        }
+    }
+}
+
+impl<'a> AstNode<'a> {
+    pub fn span(&self) -> Range {
+        match self {
+            AstNode::Local(_, r) => r.clone(),
+            AstNode::Global(_, r) => r.clone(),
+            AstNode::StrRef(_, r) => r.clone(),
+            AstNode::Const(_, _, r) => r.clone(),
+            AstNode::CPtrOffset(_, _, r) => r.clone(),
+            AstNode::SPtrOffset(_, _, r) => r.clone(),
+            AstNode::Cpy(_, r) => r.clone(),
+            AstNode::Add(_, _, _, r) => r.clone(),
+            AstNode::Sub(_, _, _, r) => r.clone(),
+            AstNode::Div(_, _, _, r) => r.clone(),
+            AstNode::Mul(_, _, _, r) => r.clone(),
+            AstNode::Rem(_, _, _, r) => r.clone(),
+            AstNode::And(_, _, _, r) => r.clone(),
+            AstNode::Or(_, _, _, r) => r.clone(),
+            AstNode::Xor(_, _, _, r) => r.clone(),
+            AstNode::Shl(_, _, _, r) => r.clone(),
+            AstNode::Shr(_, _, _, r) => r.clone(),
+            AstNode::Cast(_, _, _, _, r) => r.clone(),
+            AstNode::Neg(_, _, r) => r.clone(),
+            AstNode::Not(_, _, r) => r.clone(),
+            AstNode::Eq(_, _, _, r) => r.clone(),
+            AstNode::Ne(_, _, _, r) => r.clone(),
+            AstNode::Lt(_, _, _, r) => r.clone(),
+            AstNode::Le(_, _, _, r) => r.clone(),
+            AstNode::Gt(_, _, _, r) => r.clone(),
+            AstNode::Ge(_, _, _, r) => r.clone(),
+            AstNode::Call(_, _, _, r) => r.clone(),
+            AstNode::Ret(_, _, r) => r.clone(),
+            AstNode::RetVoid(r) => r.clone(),
+            AstNode::Stalloc(_, _, r) => r.clone(),
+            AstNode::Halloc(_, _, r) => r.clone(),
+            AstNode::Hfree(_, r) => r.clone(),
+            AstNode::Hrealloc(_, _, _, r) => r.clone(),
+            AstNode::Ptroffset(_, _, _, r) => r.clone(),
+            AstNode::Load(_, _, r) => r.clone(),
+            AstNode::Store(_, _, _, r) => r.clone(),
+            AstNode::Dbg(_, _, r) => r.clone(),
+            AstNode::Equals(_, _, r) => r.clone(),
+            AstNode::Label(_, r) => r.clone(),
+            AstNode::Jmp(_, r) => r.clone(),
+            AstNode::Br(_, _, _, _, r) => r.clone(),
+            AstNode::Sys(_, _, r) => r.clone(),
+            AstNode::Err(_, r) => r.clone(),
+        }
+    }
+
+    pub fn is_var(&self) -> bool {
+        matches!(self, AstNode::Local(..) | AstNode::Global(..))
+    }
+
+    pub fn binop(&self) -> Option<(BinOp, &Type<'a>, &AstNode<'a>, &AstNode<'a>)> {
+        let (op, t, a, b) = match self {
+            AstNode::Add(t, a, b, _) => (BinOp::Add, t, a, b),
+            AstNode::Sub(t, a, b, _) => (BinOp::Sub, t, a, b),
+            AstNode::Div(t, a, b, _) => (BinOp::Div, t, a, b),
+            AstNode::Mul(t, a, b, _) => (BinOp::Mul, t, a, b),
+            AstNode::Rem(t, a, b, _) => (BinOp::Rem, t, a, b),
+            AstNode::And(t, a, b, _) => (BinOp::And, t, a, b),
+            AstNode::Or(t, a, b, _) => (BinOp::Or, t, a, b),
+            AstNode::Xor(t, a, b, _) => (BinOp::Xor, t, a, b),
+            AstNode::Shl(t, a, b, _) => (BinOp::Shl, t, a, b),
+            AstNode::Shr(t, a, b, _) => (BinOp::Shr, t, a, b),
+            AstNode::Eq(t, a, b, _) => (BinOp::Eq, t, a, b),
+            AstNode::Ne(t, a, b, _) => (BinOp::Ne, t, a, b),
+            AstNode::Lt(t, a, b, _) => (BinOp::Lt, t, a, b),
+            AstNode::Le(t, a, b, _) => (BinOp::Le, t, a, b),
+            AstNode::Gt(t, a, b, _) => (BinOp::Gt, t, a, b),
+            AstNode::Ge(t, a, b, _) => (BinOp::Ge, t, a, b),
+            _ => return None,
+        };
+        Some((op, t, a, b))
+    }
+
+    pub fn unop(&self) -> Option<(UnOp, &Type<'a>, &AstNode<'a>)> {
+        match self {
+            AstNode::Neg(t, a, _) => Some((UnOp::Neg, t, a)),
+            AstNode::Not(t, a, _) => Some((UnOp::Not, t, a)),
+            _ => None,
+        }
+    }
+
+    /// Whether this instruction ends a basic block
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            AstNode::Ret(..)
+                | AstNode::RetVoid(..)
+                | AstNode::Jmp(..)
+                | AstNode::Br(..)
+                | AstNode::Sys(SysOp::Exit, ..)
+        )
+    }
+
+    /// Labels this instruction may jump to
+    pub fn targets(&self) -> Vec<&str> {
+        match self {
+            AstNode::Jmp(l, _) => alloc::vec![*l],
+            AstNode::Br(_, _, t, f, _) => alloc::vec![*t, *f],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Direct child nodes in evaluation order, for `Equals` the target then the instruction
+    pub fn operands(&self) -> Vec<&AstNode<'a>> {
+        if let Some((_, _, a, b)) = self.binop() {
+            return alloc::vec![a, b];
+        }
+        match self {
+            AstNode::Cpy(a, _)
+            | AstNode::Ret(_, a, _)
+            | AstNode::Stalloc(_, a, _)
+            | AstNode::Halloc(_, a, _)
+            | AstNode::Hfree(a, _)
+            | AstNode::Load(_, a, _)
+            | AstNode::Dbg(_, a, _)
+            | AstNode::Cast(_, _, _, a, _)
+            | AstNode::Neg(_, a, _)
+            | AstNode::Not(_, a, _)
+            | AstNode::Br(_, a, _, _, _) => alloc::vec![a.as_ref()],
+            AstNode::Ptroffset(_, a, b, _)
+            | AstNode::Hrealloc(_, a, b, _)
+            | AstNode::Store(_, a, b, _)
+            | AstNode::Equals(a, b, _) => alloc::vec![a.as_ref(), b.as_ref()],
+            AstNode::Call(_, f, args, _) => core::iter::once(f.as_ref())
+                .chain(args.iter().map(|x| &x.1))
+                .collect(),
+            AstNode::Sys(_, args, _) => args.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Type annotations written on this node, not including its operands
+    pub fn types(&self) -> Vec<&Type<'a>> {
+        if let Some((_, t, _, _)) = self.binop() {
+            return alloc::vec![t];
+        }
+        match self {
+            AstNode::Const(t, _, _)
+            | AstNode::CPtrOffset(t, _, _)
+            | AstNode::SPtrOffset(t, _, _)
+            | AstNode::Ret(t, _, _)
+            | AstNode::Stalloc(t, _, _)
+            | AstNode::Halloc(t, _, _)
+            | AstNode::Hrealloc(t, _, _, _)
+            | AstNode::Ptroffset(t, _, _, _)
+            | AstNode::Load(t, _, _)
+            | AstNode::Store(t, _, _, _)
+            | AstNode::Dbg(t, _, _)
+            | AstNode::Neg(t, _, _)
+            | AstNode::Not(t, _, _)
+            | AstNode::Br(t, _, _, _, _) => alloc::vec![t],
+            AstNode::Cast(_, to, from, _, _) => alloc::vec![to, from],
+            AstNode::Call(t, _, args, _) => t.iter().chain(args.iter().map(|x| &x.0)).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,882 @@
+// mistakes are rare, and reporting them like every other phase is worth the larger `Result`s
+#![allow(clippy::result_large_err)]
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::ast::{BinOp, CastOp, SysOp, Type, TypeSize, UnOp};
+use crate::diagnostic::Diagnostic;
+use crate::ir::*;
+use crate::typeck::{binop_accepts, unop_accepts};
+use crate::Range;
+
+/// Whether `name` is an identifier, the only names NIR can write after a sigil
+fn is_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Builds a lowered [`Module`] without going through NIR text, checking everything the parser
+/// and checks would as it goes. Names are given without their sigil. Errors point at the span
+/// set with `set_span`
+#[derive(Debug, Default)]
+pub struct ModuleBuilder {
+    module: Module,
+    structs_defined: Vec<bool>,
+    functions_defined: Vec<bool>,
+    span: Range,
+}
+
+impl ModuleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The module built so far
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Source location of what is built next, for frontends that have one
+    pub fn set_span(&mut self, span: Range) {
+        self.span = span;
+    }
+
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.span.clone()).with_code("build")
+    }
+
+    fn ident(&self, name: &str) -> Result<(), Diagnostic> {
+        if !is_ident(name) {
+            return Err(self.error(format!("`{name}` is not a valid identifier")));
+        }
+        Ok(())
+    }
+
+    /// Adds a header constant
+    pub fn string(&mut self, name: &str, value: &str) -> Result<StrId, Diagnostic> {
+        self.ident(name)?;
+        let sym = self.module.symbols.intern(name);
+        if self.module.strings.iter().any(|s| s.name == sym) {
+            return Err(self.error(format!(
+                "header constant `^{name}` is defined more than once"
+            )));
+        }
+        self.module.strings.push(StrConst {
+            name: sym,
+            value: value.into(),
+        });
+        Ok(StrId(self.module.strings.len() as u32 - 1))
+    }
+
+    /// Checks that `name` isn't taken by a struct or function yet
+    fn global(&mut self, name: &str) -> Result<Symbol, Diagnostic> {
+        self.ident(name)?;
+        let sym = self.module.symbols.intern(name);
+        let structs = self.module.structs.iter().map(|s| s.name);
+        if structs
+            .chain(self.module.functions.iter().map(|f| f.name))
+            .any(|s| s == sym)
+        {
+            return Err(self.error(format!("`${name}` is defined more than once")));
+        }
+        Ok(sym)
+    }
+
+    /// Checks that every struct `t` refers to was declared and that floats have a width NIR has
+    fn check_ty(&self, t: &Ty) -> Result<(), Diagnostic> {
+        match t {
+            Ty::Ptr(t) => self.check_ty(t),
+            Ty::Struct(id) if id.index() >= self.module.structs.len() => {
+                Err(self.error(format!("undefined struct {id:?}")))
+            }
+            Ty::F(TypeSize::_8 | TypeSize::_16) => Err(self.error(format!(
+                "{} isn't a type, floats are f32 or f64",
+                self.module.ty(t)
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes a struct's name known so fields and signatures can point to it before it's defined
+    pub fn declare_struct(&mut self, name: &str) -> Result<StructId, Diagnostic> {
+        let name = self.global(name)?;
+        self.module.structs.push(StructDef {
+            name,
+            fields: Vec::new(),
+            packed: false,
+        });
+        self.structs_defined.push(false);
+        Ok(StructId(self.module.structs.len() as u32 - 1))
+    }
+
+    /// Gives a declared struct its fields. Fields can only contain structs that are already
+    /// defined, anything else needs a pointer
+    pub fn define_struct(
+        &mut self,
+        id: StructId,
+        fields: &[(Option<&str>, Ty)],
+        packed: bool,
+    ) -> Result<(), Diagnostic> {
+        let name = self.module.structs[id.index()].name;
+        if self.structs_defined[id.index()] {
+            let name = self.module.name(name);
+            return Err(self.error(format!("`${name}` is defined more than once")));
+        }
+        let mut defs = Vec::new();
+        for (i, (field, ty)) in fields.iter().enumerate() {
+            self.check_ty(ty)?;
+            if let Ty::Struct(s) = ty {
+                if !self.structs_defined[s.index()] {
+                    let s = self.module.name(self.module.structs[s.index()].name);
+                    return Err(self.error(format!(
+                        "struct `${s}` must be defined before it's contained by value"
+                    )));
+                }
+            }
+            if let Some(f) = field {
+                self.ident(f)?;
+                if fields[..i].iter().any(|(g, _)| g == field) {
+                    return Err(self.error(format!("field `{f}` is defined more than once")));
+                }
+            }
+            defs.push(Field {
+                name: field.map(|f| self.module.symbols.intern(f)),
+                ty: ty.clone(),
+            });
+        }
+        let def = &mut self.module.structs[id.index()];
+        def.fields = defs;
+        def.packed = packed;
+        self.structs_defined[id.index()] = true;
+        Ok(())
+    }
+
+    /// Declares and defines a struct
+    pub fn strukt(
+        &mut self,
+        name: &str,
+        fields: &[(Option<&str>, Ty)],
+        packed: bool,
+    ) -> Result<StructId, Diagnostic> {
+        let id = self.declare_struct(name)?;
+        self.define_struct(id, fields, packed)?;
+        Ok(id)
+    }
+
+    /// Makes a function's signature known so it can be called before it's defined
+    pub fn declare(
+        &mut self,
+        name: &str,
+        ret: Option<Ty>,
+        params: &[(&str, Ty)],
+    ) -> Result<FuncId, Diagnostic> {
+        let mut locals = Vec::new();
+        for (i, (p, ty)) in params.iter().enumerate() {
+            self.check_ty(ty)?;
+            self.ident(p)?;
+            if params[..i].iter().any(|(q, _)| q == p) {
+                return Err(self.error(format!("parameter `{p}` is defined more than once")));
+            }
+            locals.push(Local {
+                name: self.module.symbols.intern(p),
+                ty: ty.clone(),
+            });
+        }
+        if let Some(ret) = &ret {
+            self.check_ty(ret)?;
+        }
+        let name = self.global(name)?;
+        self.module.functions.push(Function {
+            name,
+            ret,
+            params: params.len(),
+            locals,
+            insts: Vec::new(),
+            spans: Vec::new(),
+            blocks: Vec::new(),
+            span: self.span.clone(),
+        });
+        self.functions_defined.push(false);
+        Ok(FuncId(self.module.functions.len() as u32 - 1))
+    }
+
+    /// Starts the body of a declared function, it's added to the module by
+    /// [`FunctionBuilder::finish`]
+    pub fn define(&mut self, id: FuncId) -> Result<FunctionBuilder<'_>, Diagnostic> {
+        let f = &self.module.functions[id.index()];
+        if self.functions_defined[id.index()] {
+            let name = self.module.name(f.name);
+            return Err(self.error(format!("`${name}` is defined more than once")));
+        }
+        let mut f = f.clone();
+        f.span = self.span.clone();
+        f.blocks.push(Block {
+            label: None,
+            insts: Vec::new(),
+            term: Terminator::Unreachable,
+            term_span: 0..0,
+        });
+        let span = self.span.clone();
+        Ok(FunctionBuilder {
+            module: self,
+            id,
+            f,
+            owner: Vec::new(),
+            terminated: alloc::vec![false],
+            span,
+        })
+    }
+
+    /// Declares a function and starts its body
+    pub fn function(
+        &mut self,
+        name: &str,
+        ret: Option<Ty>,
+        params: &[(&str, Ty)],
+    ) -> Result<FunctionBuilder<'_>, Diagnostic> {
+        let id = self.declare(name, ret, params)?;
+        self.define(id)
+    }
+
+    /// The finished module, an error if a declared struct or function was never defined
+    pub fn finish(self) -> Result<Module, Diagnostic> {
+        for (s, defined) in self.module.structs.iter().zip(&self.structs_defined) {
+            if !defined {
+                let name = self.module.name(s.name);
+                return Err(self.error(format!("struct `${name}` is never defined")));
+            }
+        }
+        for (f, defined) in self.module.functions.iter().zip(&self.functions_defined) {
+            if !defined {
+                let name = self.module.name(f.name);
+                return Err(self.error(format!("function `${name}` is never defined")));
+            }
+        }
+        Ok(self.module)
+    }
+}
+
+/// Builds the body of one function, see [`ModuleBuilder::define`]
+pub struct FunctionBuilder<'m> {
+    module: &'m mut ModuleBuilder,
+    id: FuncId,
+    f: Function,
+    /// Block of every instruction, by `ValueId`
+    owner: Vec<BlockId>,
+    terminated: Vec<bool>,
+    span: Range,
+}
+
+impl<'m> FunctionBuilder<'m> {
+    pub fn id(&self) -> FuncId {
+        self.id
+    }
+
+    /// The `n`th parameter
+    pub fn param(&self, n: usize) -> Option<LocalId> {
+        (n < self.f.params).then_some(LocalId(n as u32))
+    }
+
+    /// Source location of the instructions built next
+    pub fn set_span(&mut self, span: Range) {
+        self.span = span;
+    }
+
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.span.clone()).with_code("build")
+    }
+
+    fn show(&self, t: &Ty) -> Type<'_> {
+        self.module.module.ty(t)
+    }
+
+    fn ident(&self, name: &str) -> Result<(), Diagnostic> {
+        if !is_ident(name) {
+            return Err(self.error(format!("`{name}` is not a valid identifier")));
+        }
+        Ok(())
+    }
+
+    pub fn local(&mut self, name: &str, ty: Ty) -> Result<LocalId, Diagnostic> {
+        self.module.check_ty(&ty)?;
+        self.ident(name)?;
+        let sym = self.module.module.symbols.intern(name);
+        if self.f.locals.iter().any(|l| l.name == sym) {
+            return Err(self.error(format!("local `&{name}` is defined more than once")));
+        }
+        self.f.locals.push(Local { name: sym, ty });
+        Ok(LocalId(self.f.locals.len() as u32 - 1))
+    }
+
+    /// The block execution starts in, it has no label
+    pub fn entry(&self) -> BlockId {
+        BlockId(0)
+    }
+
+    /// Adds an empty block
+    pub fn block(&mut self, label: &str) -> Result<BlockId, Diagnostic> {
+        self.ident(label)?;
+        let sym = self.module.module.symbols.intern(label);
+        if self.f.blocks.iter().any(|b| b.label == Some(sym)) {
+            return Err(self.error(format!("label `.{label}` is defined more than once")));
+        }
+        self.f.blocks.push(Block {
+            label: Some(sym),
+            insts: Vec::new(),
+            term: Terminator::Unreachable,
+            term_span: 0..0,
+        });
+        self.terminated.push(false);
+        Ok(BlockId(self.f.blocks.len() as u32 - 1))
+    }
+
+    /// Appends to block `b`, which must belong to this function. Ids are plain indices, so a
+    /// block of another function is only caught if this one has fewer blocks
+    pub fn at(&mut self, b: BlockId) -> Result<BlockBuilder<'_, 'm>, Diagnostic> {
+        if b.index() >= self.f.blocks.len() {
+            return Err(self.error(format!("undefined block {b:?}")));
+        }
+        Ok(BlockBuilder { f: self, block: b })
+    }
+
+    /// Adds the function to the module, every block must end in a terminator
+    pub fn finish(self) -> Result<FuncId, Diagnostic> {
+        if let Some(b) = self.terminated.iter().position(|t| !t) {
+            let name = match self.f.blocks[b].label {
+                Some(l) => format!("block `.{}`", self.module.module.name(l)),
+                None => "the entry block".into(),
+            };
+            return Err(self.error(format!("{name} doesn't end in a terminator")));
+        }
+        self.module.module.functions[self.id.index()] = self.f;
+        self.module.functions_defined[self.id.index()] = true;
+        Ok(self.id)
+    }
+
+    /// Type of `v`, which must have been built earlier in block `b`
+    fn value(&self, b: BlockId, v: ValueId) -> Result<Ty, Diagnostic> {
+        if self.owner.get(v.index()) != Some(&b) {
+            return Err(self.error(format!(
+                "value %{} isn't defined earlier in this block",
+                v.0
+            )));
+        }
+        self.f.insts[v.index()].ty.clone().ok_or_else(|| {
+            self.error(format!(
+                "value %{} is the result of a void instruction",
+                v.0
+            ))
+        })
+    }
+
+    fn expect(&self, b: BlockId, v: ValueId, expected: &Ty) -> Result<(), Diagnostic> {
+        let t = self.value(b, v)?;
+        if t != *expected {
+            return Err(self.error(format!(
+                "expected {}, found {}",
+                self.show(expected),
+                self.show(&t)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that `v` is an integer, `what` names its role
+    fn int(&self, b: BlockId, v: ValueId, what: &str) -> Result<(), Diagnostic> {
+        let t = self.value(b, v)?;
+        if !matches!(t, Ty::I(_) | Ty::U(_)) {
+            return Err(self.error(format!(
+                "{what} must be an integer, found {}",
+                self.show(&t)
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_open(&self, b: BlockId) -> Result<(), Diagnostic> {
+        if self.terminated[b.index()] {
+            return Err(self.error("the block already ends in a terminator"));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, b: BlockId, kind: InstKind, ty: Option<Ty>) -> Result<ValueId, Diagnostic> {
+        self.check_open(b)?;
+        let v = self.f.push(kind, ty, self.span.clone());
+        self.owner.push(b);
+        self.f.blocks[b.index()].insts.push(v);
+        Ok(v)
+    }
+
+    fn terminate(&mut self, b: BlockId, term: Terminator) -> Result<(), Diagnostic> {
+        self.check_open(b)?;
+        if let Some(target) = term
+            .successors()
+            .into_iter()
+            .find(|t| t.index() >= self.f.blocks.len())
+        {
+            return Err(self.error(format!("jump to undefined block {target:?}")));
+        }
+        let block = &mut self.f.blocks[b.index()];
+        block.term = term;
+        block.term_span = self.span.clone();
+        self.terminated[b.index()] = true;
+        Ok(())
+    }
+}
+
+macro_rules! binops {
+    ($($name:ident = $op:ident),*) => {
+        $(
+            #[doc = concat!("`", stringify!($name), "`, see [`BlockBuilder::binary`]")]
+            pub fn $name(&mut self, ty: Ty, a: ValueId, b: ValueId) -> Result<ValueId, Diagnostic> {
+                self.binary(BinOp::$op, ty, a, b)
+            }
+        )*
+    };
+}
+
+/// Appends instructions to one block, see [`FunctionBuilder::at`]. Operands must be values built
+/// earlier in the same block, locals carry values between blocks. A value of another function
+/// isn't caught if this function has a value with the same id in a block with the same id
+pub struct BlockBuilder<'f, 'm> {
+    f: &'f mut FunctionBuilder<'m>,
+    block: BlockId,
+}
+
+impl BlockBuilder<'_, '_> {
+    pub fn id(&self) -> BlockId {
+        self.block
+    }
+
+    pub fn set_span(&mut self, span: Range) {
+        self.f.set_span(span);
+    }
+
+    fn emit(&mut self, kind: InstKind, ty: Option<Ty>) -> Result<ValueId, Diagnostic> {
+        self.f.emit(self.block, kind, ty)
+    }
+
+    fn expect(&self, v: ValueId, expected: &Ty) -> Result<(), Diagnostic> {
+        self.f.expect(self.block, v, expected)
+    }
+
+    /// An integer, `bool` or null pointer constant. Signed values are given as their bits
+    pub fn int(&mut self, ty: Ty, value: u64) -> Result<ValueId, Diagnostic> {
+        let fits = match &ty {
+            Ty::I(s) | Ty::U(s) => s.bits() == 64 || value < 1 << s.bits(),
+            Ty::Bool => value <= 1,
+            Ty::Ptr(_) => value == 0,
+            Ty::F(_) | Ty::Struct(_) => false,
+        };
+        if !fits {
+            let t = self.f.show(&ty);
+            return Err(self.f.error(format!("constant {value} doesn't fit in {t}")));
+        }
+        self.emit(InstKind::Const(value), Some(ty))
+    }
+
+    pub fn float(&mut self, ty: Ty, value: f64) -> Result<ValueId, Diagnostic> {
+        let bits = match ty {
+            Ty::F(TypeSize::_32) => u64::from((value as f32).to_bits()),
+            Ty::F(TypeSize::_64) => value.to_bits(),
+            _ => {
+                let t = self.f.show(&ty);
+                return Err(self.f.error(format!("{t} is not a float type")));
+            }
+        };
+        self.emit(InstKind::Const(bits), Some(ty))
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<ValueId, Diagnostic> {
+        self.emit(InstKind::Const(u64::from(value)), Some(Ty::Bool))
+    }
+
+    /// Address of a header constant
+    pub fn str(&mut self, id: StrId) -> Result<ValueId, Diagnostic> {
+        if id.index() >= self.f.module.module.strings.len() {
+            return Err(self.f.error(format!("undefined header constant {id:?}")));
+        }
+        self.emit(InstKind::Str(id), Some(Ty::U(TypeSize::_8).ptr()))
+    }
+
+    /// Byte offset of the `n`th element of an array of `ty`, like `cptroffset`
+    pub fn elem_offset(&mut self, ty: Ty, n: u64) -> Result<ValueId, Diagnostic> {
+        self.f.module.check_ty(&ty)?;
+        self.emit(InstKind::ElemOffset(ty, n), Some(Ty::U(TypeSize::_64)))
+    }
+
+    /// Byte offset of the field reached by taking the field indices in `path`, like `sptroffset`
+    pub fn field_offset(&mut self, ty: Ty, path: &[u32]) -> Result<ValueId, Diagnostic> {
+        let m = &self.f.module;
+        m.check_ty(&ty)?;
+        let mut cur = &ty;
+        for i in path {
+            let Ty::Struct(id) = cur else {
+                let t = self.f.show(cur);
+                return Err(self.f.error(format!("{t} is not a struct")));
+            };
+            let def = &m.module.structs[id.index()];
+            let Some(field) = def.fields.get(*i as usize) else {
+                let name = m.module.name(def.name);
+                return Err(self.f.error(format!("struct `${name}` has no field {i}")));
+            };
+            cur = &field.ty;
+        }
+        let kind = InstKind::FieldOffset(ty.clone(), path.into());
+        self.emit(kind, Some(Ty::U(TypeSize::_64)))
+    }
+
+    pub fn read(&mut self, local: LocalId) -> Result<ValueId, Diagnostic> {
+        let Some(l) = self.f.f.locals.get(local.index()) else {
+            return Err(self.f.error(format!("undefined local {local:?}")));
+        };
+        let ty = l.ty.clone();
+        self.emit(InstKind::ReadLocal(local), Some(ty))
+    }
+
+    pub fn write(&mut self, local: LocalId, v: ValueId) -> Result<(), Diagnostic> {
+        let Some(l) = self.f.f.locals.get(local.index()) else {
+            return Err(self.f.error(format!("undefined local {local:?}")));
+        };
+        self.expect(v, &l.ty.clone())?;
+        self.emit(InstKind::WriteLocal(local, v), None)?;
+        Ok(())
+    }
+
+    /// Both operands have type `ty`, the result is `bool` for comparisons and `ty` otherwise
+    pub fn binary(
+        &mut self,
+        op: BinOp,
+        ty: Ty,
+        a: ValueId,
+        b: ValueId,
+    ) -> Result<ValueId, Diagnostic> {
+        let t = self.f.show(&ty);
+        if !binop_accepts(op, &t) {
+            return Err(self
+                .f
+                .error(format!("`{}` is not defined for {t}", op.name())));
+        }
+        self.expect(a, &ty)?;
+        self.expect(b, &ty)?;
+        let result = if op.is_comparison() {
+            Ty::Bool
+        } else {
+            ty.clone()
+        };
+        self.emit(InstKind::Binary(op, ty, a, b), Some(result))
+    }
+
+    binops!(
+        add = Add,
+        sub = Sub,
+        mul = Mul,
+        div = Div,
+        rem = Rem,
+        and = And,
+        or = Or,
+        xor = Xor,
+        shl = Shl,
+        shr = Shr,
+        eq = Eq,
+        ne = Ne,
+        lt = Lt,
+        le = Le,
+        gt = Gt,
+        ge = Ge
+    );
+
+    pub fn unary(&mut self, op: UnOp, ty: Ty, a: ValueId) -> Result<ValueId, Diagnostic> {
+        let t = self.f.show(&ty);
+        if !unop_accepts(op, &t) {
+            return Err(self
+                .f
+                .error(format!("`{}` is not defined for {t}", op.name())));
+        }
+        self.expect(a, &ty)?;
+        self.emit(InstKind::Unary(op, ty.clone(), a), Some(ty))
+    }
+
+    pub fn neg(&mut self, ty: Ty, a: ValueId) -> Result<ValueId, Diagnostic> {
+        self.unary(UnOp::Neg, ty, a)
+    }
+
+    pub fn not(&mut self, ty: Ty, a: ValueId) -> Result<ValueId, Diagnostic> {
+        self.unary(UnOp::Not, ty, a)
+    }
+
+    /// Converts `v` to `to`
+    pub fn cast(&mut self, op: CastOp, to: Ty, v: ValueId) -> Result<ValueId, Diagnostic> {
+        self.f.module.check_ty(&to)?;
+        let from = self.f.value(self.block, v)?;
+        let (f, t) = (self.f.show(&from), self.f.show(&to));
+        if !op.is_legal(&f, &t) {
+            return Err(self
+                .f
+                .error(format!("`{}` can't convert {f} to {t}", op.name())));
+        }
+        self.emit(InstKind::Cast(op, from, v), Some(to))
+    }
+
+    /// Calls a function of the module, the value is void if the function returns nothing
+    pub fn call(&mut self, callee: FuncId, args: &[ValueId]) -> Result<ValueId, Diagnostic> {
+        let Some(target) = self.f.module.module.functions.get(callee.index()) else {
+            return Err(self.f.error(format!("undefined function {callee:?}")));
+        };
+        let name = self.f.module.module.name(target.name);
+        if args.len() != target.params {
+            return Err(self.f.error(format!(
+                "`${name}` takes {} arguments, but {} were given",
+                target.params,
+                args.len()
+            )));
+        }
+        for (a, p) in args.iter().zip(&target.locals) {
+            self.expect(*a, &p.ty)?;
+        }
+        let ret = target.ret.clone();
+        self.emit(InstKind::Call(Callee::Function(callee), args.into()), ret)
+    }
+
+    /// Calls a function the embedder provides, the interpreter checks the signature when it runs
+    pub fn call_host(
+        &mut self,
+        name: &str,
+        ret: Option<Ty>,
+        args: &[ValueId],
+    ) -> Result<ValueId, Diagnostic> {
+        if let Some(ret) = &ret {
+            self.f.module.check_ty(ret)?;
+        }
+        self.f.ident(name)?;
+        for a in args {
+            self.f.value(self.block, *a)?;
+        }
+        if self.f.module.module.function(name).is_some() {
+            return Err(self.f.error(format!(
+                "`${name}` is a function of the module, not of the host"
+            )));
+        }
+        let name = self.f.module.module.symbols.intern(name);
+        self.emit(InstKind::Call(Callee::Host(name), args.into()), ret)
+    }
+
+    /// Reserves `n` elements of `ty` on the stack until the function returns
+    pub fn stalloc(&mut self, ty: Ty, n: ValueId) -> Result<ValueId, Diagnostic> {
+        self.f.module.check_ty(&ty)?;
+        self.f.int(self.block, n, "element count")?;
+        self.emit(InstKind::Stalloc(ty.clone(), n), Some(ty.ptr()))
+    }
+
+    pub fn halloc(&mut self, ty: Ty, n: ValueId) -> Result<ValueId, Diagnostic> {
+        self.f.module.check_ty(&ty)?;
+        self.f.int(self.block, n, "element count")?;
+        self.emit(InstKind::Halloc(ty.clone(), n), Some(ty.ptr()))
+    }
+
+    pub fn hrealloc(&mut self, ty: Ty, p: ValueId, n: ValueId) -> Result<ValueId, Diagnostic> {
+        self.expect(p, &ty.clone().ptr())?;
+        self.f.int(self.block, n, "element count")?;
+        self.emit(InstKind::Hrealloc(ty.clone(), p, n), Some(ty.ptr()))
+    }
+
+    pub fn hfree(&mut self, p: ValueId) -> Result<(), Diagnostic> {
+        let t = self.f.value(self.block, p)?;
+        if !matches!(t, Ty::Ptr(_)) {
+            let t = self.f.show(&t);
+            return Err(self.f.error(format!("`hfree` needs a pointer, found {t}")));
+        }
+        self.emit(InstKind::Hfree(p), None)?;
+        Ok(())
+    }
+
+    /// Pointer to the `i`th element of type `ty` after `p`
+    pub fn ptroffset(&mut self, ty: Ty, p: ValueId, i: ValueId) -> Result<ValueId, Diagnostic> {
+        self.expect(p, &ty.clone().ptr())?;
+        self.f.int(self.block, i, "offset")?;
+        self.emit(InstKind::Ptroffset(ty.clone(), p, i), Some(ty.ptr()))
+    }
+
+    pub fn load(&mut self, ty: Ty, p: ValueId) -> Result<ValueId, Diagnostic> {
+        self.expect(p, &ty.clone().ptr())?;
+        self.emit(InstKind::Load(ty.clone(), p), Some(ty))
+    }
+
+    pub fn store(&mut self, ty: Ty, p: ValueId, v: ValueId) -> Result<(), Diagnostic> {
+        self.expect(p, &ty.clone().ptr())?;
+        self.expect(v, &ty)?;
+        self.emit(InstKind::Store(ty, p, v), None)?;
+        Ok(())
+    }
+
+    pub fn dbg(&mut self, ty: Ty, v: ValueId) -> Result<(), Diagnostic> {
+        self.expect(v, &ty)?;
+        self.emit(InstKind::Dbg(ty, v), None)?;
+        Ok(())
+    }
+
+    /// A system operation, the value is void for `exit` and `free`. `exit` should be followed by
+    /// [`BlockBuilder::unreachable`]
+    pub fn sys(&mut self, op: SysOp, args: &[ValueId]) -> Result<ValueId, Diagnostic> {
+        let params = op.params();
+        if args.len() != params.len() {
+            return Err(self.f.error(format!(
+                "`sys {}` takes {} operands, but {} were given",
+                op.name(),
+                params.len(),
+                args.len()
+            )));
+        }
+        for (a, t) in args.iter().zip(&params) {
+            self.expect(*a, &Ty::primitive(t).expect("sys types are primitive"))?;
+        }
+        let ret = op.ret().and_then(|t| Ty::primitive(&t));
+        self.emit(InstKind::Sys(op, args.into()), ret)
+    }
+
+    /// Returns `v`, which must be given exactly when the function returns a value
+    pub fn ret(&mut self, v: Option<ValueId>) -> Result<(), Diagnostic> {
+        match (&self.f.f.ret, v) {
+            (Some(ty), Some(v)) => self.expect(v, &ty.clone())?,
+            (None, None) => (),
+            (Some(ty), None) => {
+                let t = self.f.show(ty);
+                return Err(self.f.error(format!(
+                    "`ret` needs a value of type {t} in a non-void function"
+                )));
+            }
+            (None, Some(_)) => {
+                return Err(self
+                    .f
+                    .error("void function can only use `ret` without a type and operand"))
+            }
+        }
+        self.f.terminate(self.block, Terminator::Ret(v))
+    }
+
+    pub fn jmp(&mut self, target: BlockId) -> Result<(), Diagnostic> {
+        self.f.terminate(self.block, Terminator::Jmp(target))
+    }
+
+    /// Jumps to `then` if `c` is non-zero, otherwise to `els`
+    pub fn br(&mut self, c: ValueId, then: BlockId, els: BlockId) -> Result<(), Diagnostic> {
+        let t = self.f.value(self.block, c)?;
+        if !matches!(t, Ty::I(_) | Ty::U(_) | Ty::Bool) {
+            let t = self.f.show(&t);
+            return Err(self
+                .f
+                .error(format!("`br` needs a bool or integer, not {t}")));
+        }
+        self.f.terminate(self.block, Terminator::Br(c, then, els))
+    }
+
+    /// Ends the block after a `sys exit`, which has to be its last instruction
+    pub fn unreachable(&mut self) -> Result<(), Diagnostic> {
+        let f = &self.f.f;
+        let last = f.blocks[self.block.index()].insts.last();
+        if !last.is_some_and(|v| matches!(f.insts[v.index()].kind, InstKind::Sys(SysOp::Exit, _))) {
+            return Err(self
+                .f
+                .error("`unreachable` can only end a block after `sys exit`"));
+        }
+        self.f.terminate(self.block, Terminator::Unreachable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::TypeSize::*;
+
+    fn message<T>(res: Result<T, Diagnostic>) -> String {
+        match res {
+            Ok(_) => panic!("expected an error"),
+            Err(d) => d.message,
+        }
+    }
+
+    #[test]
+    fn undefined_blocks() {
+        let mut m = ModuleBuilder::new();
+        let mut f = m.function("main", None, &[]).unwrap();
+        assert_eq!(message(f.at(BlockId(1))), "undefined block BlockId(1)");
+        let b = f.block("next").unwrap();
+        assert!(f.at(b).is_ok());
+    }
+
+    #[test]
+    fn unreachable_needs_exit() {
+        let mut m = ModuleBuilder::new();
+        let mut f = m.function("main", None, &[]).unwrap();
+        let e = f.entry();
+        let mut b = f.at(e).unwrap();
+        assert_eq!(
+            message(b.unreachable()),
+            "`unreachable` can only end a block after `sys exit`"
+        );
+        let code = b.int(Ty::I(_32), 0).unwrap();
+        b.sys(SysOp::Exit, &[code]).unwrap();
+        b.unreachable().unwrap();
+        f.finish().unwrap();
+        m.finish().unwrap();
+    }
+
+    #[test]
+    fn invalid_names() {
+        let mut m = ModuleBuilder::new();
+        assert_eq!(
+            message(m.string("a b", "x")),
+            "`a b` is not a valid identifier"
+        );
+        assert_eq!(
+            message(m.declare("", None, &[])),
+            "`` is not a valid identifier"
+        );
+        assert!(m.declare_struct("1S").is_err());
+        assert!(m.function("f", None, &[("a-b", Ty::Bool)]).is_err());
+        let mut f = m.function("main", None, &[]).unwrap();
+        assert!(f.local("x.y", Ty::Bool).is_err());
+        assert!(f.block("$l").is_err());
+        assert!(f.local("_x1", Ty::Bool).is_ok());
+    }
+
+    #[test]
+    fn float_widths() {
+        let mut m = ModuleBuilder::new();
+        assert_eq!(
+            message(m.function("f", Some(Ty::F(_16)), &[])),
+            "f16 isn't a type, floats are f32 or f64"
+        );
+        let mut f = m.function("main", None, &[]).unwrap();
+        assert!(f.local("x", Ty::F(_8).ptr()).is_err());
+        let e = f.entry();
+        let mut b = f.at(e).unwrap();
+        let one = b.int(Ty::U(_32), 1).unwrap();
+        assert!(b.cast(CastOp::Itof, Ty::F(_16), one).is_err());
+        assert!(b.cast(CastOp::Itof, Ty::F(_32), one).is_ok());
+    }
+
+    /// Ids are indices into the function being built, the builder can't tell whose they are
+    #[test]
+    fn ids_of_other_functions() {
+        let mut m = ModuleBuilder::new();
+        let mut f = m.function("f", None, &[]).unwrap();
+        let e = f.entry();
+        let mut b = f.at(e).unwrap();
+        let foreign = b.int(Ty::U(_32), 1).unwrap();
+        b.ret(None).unwrap();
+        f.finish().unwrap();
+
+        let mut g = m.function("g", None, &[]).unwrap();
+        let e = g.entry();
+        let mut b = g.at(e).unwrap();
+        assert!(b.dbg(Ty::U(_32), foreign).is_err());
+        b.int(Ty::U(_32), 2).unwrap();
+        // now `g` has a value with the same id in a block with the same id
+        assert!(b.dbg(Ty::U(_32), foreign).is_ok());
+    }
+}
@@ -0,0 +1,466 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+
+use crate::{ParserErr, Range};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// A place in the source with a short explanation
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Range,
+    pub message: String,
+}
+
+/// An error or warning from any phase, pointing into the source
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The phase that reported it, e.g. `syntax` or `type`
+    pub code: Option<&'static str>,
+    pub message: String,
+    /// Where the problem is
+    pub span: Range,
+    /// Shown next to the underlined `span`, empty for none
+    pub label: String,
+    /// Related places, like an earlier definition
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+    /// For syntax errors, what the parser would have accepted instead
+    pub expected: Vec<String>,
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, message: String, span: Range) -> Self {
+        Diagnostic {
+            severity,
+            code: None,
+            message,
+            span,
+            label: String::new(),
+            secondary: Vec::new(),
+            notes: Vec::new(),
+            help: None,
+            expected: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>, span: Range) -> Self {
+        Self::new(Severity::Error, message.into(), span)
+    }
+
+    pub fn warning(message: impl Into<String>, span: Range) -> Self {
+        Self::new(Severity::Warning, message.into(), span)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Sets the code unless one was set already
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code.get_or_insert(code);
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Range, message: impl Into<String>) -> Self {
+        self.secondary.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+impl From<ParserErr> for Diagnostic {
+    fn from(e: ParserErr) -> Self {
+        let expected: Vec<String> = e.expected.iter().map(|x| x.to_string()).collect();
+        let found = match &e.next {
+            Some((_, s)) => format!("`{s}`"),
+            None => "the end of the file".into(),
+        };
+        let message = match expected.as_slice() {
+            [] => format!("unexpected {found}"),
+            [x] => format!("expected {x}, found {found}"),
+            [xs @ .., x] => format!("expected {} or {x}, found {found}", xs.join(", ")),
+        };
+        let d = match e.next {
+            Some((_, s)) => Diagnostic::error(message, e.end_idx..e.end_idx + s.len())
+                .with_label("unexpected token"),
+            None => Diagnostic::error(message, e.end_idx..e.end_idx),
+        };
+        Diagnostic {
+            expected,
+            ..d.with_code("syntax")
+        }
+    }
+}
+
+/// Line and column of byte offsets into a source file
+pub struct SourceMap<'s> {
+    src: &'s str,
+    /// Offset of the first byte of every line
+    starts: Vec<usize>,
+}
+
+impl<'s> SourceMap<'s> {
+    pub fn new(src: &'s str) -> Self {
+        let newlines = src.match_indices('\n').map(|(i, _)| i + 1);
+        SourceMap {
+            src,
+            starts: core::iter::once(0).chain(newlines).collect(),
+        }
+    }
+
+    /// Clamps `offset` into the source and onto a character boundary
+    fn clamp(&self, offset: usize) -> usize {
+        let mut offset = offset.min(self.src.len());
+        while !self.src.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        offset
+    }
+
+    /// Index of the line containing `offset`, starting at 0
+    fn line_index(&self, offset: usize) -> usize {
+        self.starts.partition_point(|s| *s <= offset) - 1
+    }
+
+    /// Text of a line without its line break
+    pub fn line(&self, index: usize) -> &'s str {
+        let end = self
+            .starts
+            .get(index + 1)
+            .copied()
+            .unwrap_or(self.src.len());
+        let line = &self.src[self.starts[index]..end];
+        line.trim_end_matches('\n').trim_end_matches('\r')
+    }
+
+    /// Line and column of `offset`, both starting at 1. Columns count characters, not bytes
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = self.clamp(offset);
+        let line = self.line_index(offset);
+        let col = self.src[self.starts[line]..offset].chars().count();
+        (line + 1, col + 1)
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Formats `d` with the source lines it points to underlined, like
+///
+/// ```text
+/// error[type]: expected u32, found u64
+///  --> main.nir:3:19
+///   |
+/// 3 |     &x = add(u32) &a, !(u32)1
+///   |                   ^^
+/// ```
+///
+/// `file` is shown in front of the line and column, `color` adds ANSI escape codes
+pub fn render(d: &Diagnostic, src: &str, file: &str, color: bool) -> String {
+    let paint = |code: &'static str| if color { code } else { "" };
+    let (reset, bold, blue) = (paint(RESET), paint(BOLD), paint(BLUE));
+    let severity = paint(match d.severity {
+        Severity::Error => RED,
+        Severity::Warning => YELLOW,
+    });
+    let map = SourceMap::new(src);
+
+    let mut out = String::new();
+    let _ = write!(out, "{severity}{}", d.severity.name());
+    if let Some(code) = d.code {
+        let _ = write!(out, "[{code}]");
+    }
+    let _ = writeln!(out, "{reset}{bold}: {}{reset}", d.message);
+
+    // the primary label first on its line, then everything in source order
+    let mut labels: Vec<(bool, &Range, &str)> = Vec::new();
+    labels.push((true, &d.span, &d.label));
+    labels.extend(
+        d.secondary
+            .iter()
+            .map(|l| (false, &l.span, l.message.as_str())),
+    );
+    labels.sort_by_key(|(primary, span, _)| (map.line_col(span.start).0, !primary));
+
+    let last_line = labels.iter().map(|l| map.line_col(l.1.start).0).max();
+    let width = format!("{}", last_line.unwrap_or(1)).len();
+    let gutter = |out: &mut String, line: Option<usize>| {
+        let n = line.map(|n| format!("{n}")).unwrap_or_default();
+        let _ = write!(out, "{blue}{n:>width$} |{reset}");
+    };
+
+    let (line, col) = map.line_col(d.span.start);
+    let _ = writeln!(out, "{:width$}{blue}-->{reset} {file}:{line}:{col}", "");
+    gutter(&mut out, None);
+    out.push('\n');
+    let mut shown = None;
+    for (primary, span, message) in labels {
+        let start = map.clamp(span.start);
+        let index = map.line_index(start);
+        let text = map.line(index);
+        if shown != Some(index) {
+            if shown.is_some_and(|s| s + 1 < index) {
+                let _ = writeln!(out, "{blue}...{reset}");
+            }
+            gutter(&mut out, Some(index + 1));
+            let _ = writeln!(out, " {text}");
+            shown = Some(index);
+        }
+        // the underline stops at the end of the line, and covers at least one character
+        let before = &src[map.starts[index]..start];
+        let end = map
+            .clamp(span.end)
+            .clamp(start, map.starts[index] + text.len());
+        let len = src[start..end].chars().count().max(1);
+        let indent: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let (mark, paint) = if primary {
+            ('^', severity)
+        } else {
+            ('-', blue)
+        };
+        gutter(&mut out, None);
+        let marks: String = core::iter::repeat_n(mark, len).collect();
+        let _ = write!(out, " {indent}{paint}{marks}");
+        if !message.is_empty() {
+            let _ = write!(out, " {message}");
+        }
+        let _ = writeln!(out, "{reset}");
+    }
+    for note in &d.notes {
+        let _ = writeln!(
+            out,
+            "{:width$} {blue}={reset} {bold}note{reset}: {note}",
+            ""
+        );
+    }
+    if let Some(help) = &d.help {
+        let _ = writeln!(
+            out,
+            "{:width$} {blue}={reset} {bold}help{reset}: {help}",
+            ""
+        );
+    }
+    out
+}
+
+/// Writes `value` as a JSON string
+fn json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Writes the items of a JSON array of strings
+fn json_strs<'s>(out: &mut String, values: impl IntoIterator<Item = &'s String>) {
+    out.push('[');
+    for (i, x) in values.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_str(out, x);
+    }
+    out.push(']');
+}
+
+/// Writes `span` as its byte offsets and where it starts and ends
+fn json_span(out: &mut String, map: &SourceMap, span: &Range) {
+    let (line, col) = map.line_col(span.start);
+    let (end_line, end_col) = map.line_col(span.end);
+    let _ = write!(
+        out,
+        "\"start\":{},\"end\":{},\"line\":{line},\"column\":{col},\"end_line\":{end_line},\"end_column\":{end_col}",
+        span.start, span.end
+    );
+}
+
+/// Formats `d` as a JSON object on a single line, for editors and other tools
+///
+/// Offsets are in bytes, lines and columns start at 1 and columns count characters.
+/// Fields without a value are `null` or empty arrays, they are never left out.
+pub fn to_json(d: &Diagnostic, src: &str, file: &str) -> String {
+    let map = SourceMap::new(src);
+    let mut out = String::from("{\"file\":");
+    json_str(&mut out, file);
+    let _ = write!(out, ",\"severity\":\"{}\",\"code\":", d.severity.name());
+    match d.code {
+        Some(code) => json_str(&mut out, code),
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"message\":");
+    json_str(&mut out, &d.message);
+    out.push(',');
+    json_span(&mut out, &map, &d.span);
+    out.push_str(",\"label\":");
+    json_str(&mut out, &d.label);
+    out.push_str(",\"secondary\":[");
+    for (i, l) in d.secondary.iter().enumerate() {
+        out.push_str(if i > 0 { ",{" } else { "{" });
+        json_span(&mut out, &map, &l.span);
+        out.push_str(",\"message\":");
+        json_str(&mut out, &l.message);
+        out.push('}');
+    }
+    out.push_str("],\"notes\":");
+    json_strs(&mut out, &d.notes);
+    out.push_str(",\"help\":");
+    match &d.help {
+        Some(help) => json_str(&mut out, help),
+        None => out.push_str("null"),
+    }
+    out.push_str(",\"expected\":");
+    json_strs(&mut out, &d.expected);
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+
+    fn first(src: &str) -> Diagnostic {
+        parse_module(src).unwrap_err().remove(0)
+    }
+
+    #[test]
+    fn parser_errors() {
+        let d = first("struct $S {a=u32, u8}\n");
+        assert_eq!(
+            d.message,
+            "expected fields that are all named or all positional, found `{`"
+        );
+        assert_eq!(d.span, 10..11);
+        let d = first("fn $main() () {\n    !(u32)1 = cpy !(u32)1\n}\n");
+        assert_eq!(d.message, "expected statement, found `!`");
+        let d = first("fn $main() () {\n");
+        assert_eq!(
+            d.message,
+            "expected statement or `}`, found the end of the file"
+        );
+        assert_eq!(d.expected, ["statement", "`}`"]);
+    }
+
+    #[test]
+    fn renders_labels() {
+        let src = "fn $f() () {\n}\nfn $f() () {\n}\n";
+        let d = Diagnostic::error("`$f` is defined more than once", 15..29)
+            .with_code("resolve")
+            .with_label("redefined")
+            .with_secondary(0..14, "first defined here")
+            .with_note("functions share one namespace")
+            .with_help("rename one of them");
+        assert_eq!(
+            render(&d, src, "a.nir", false),
+            "error[resolve]: `$f` is defined more than once
+ --> a.nir:3:1
+  |
+1 | fn $f() () {
+  | ------------ first defined here
+...
+3 | fn $f() () {
+  | ^^^^^^^^^^^^ redefined
+  = note: functions share one namespace
+  = help: rename one of them
+"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let src = "fn $f() () {\n}\n; é\nfn $f() () {\n}\n";
+        let d = Diagnostic::error("`$f` is \"defined\"\ttwice", 20..34)
+            .with_code("resolve")
+            .with_label("redefined")
+            .with_secondary(0..14, "first defined here")
+            .with_secondary(17..19, "é")
+            .with_note("one namespace")
+            .with_note(r"see \ docs");
+        assert_eq!(
+            to_json(&d, src, "dir/a.nir"),
+            concat!(
+                r#"{"file":"dir/a.nir","severity":"error","code":"resolve","#,
+                r#""message":"`$f` is \"defined\"\ttwice","#,
+                r#""start":20,"end":34,"line":4,"column":1,"end_line":5,"end_column":2,"#,
+                r#""label":"redefined","secondary":["#,
+                r#"{"start":0,"end":14,"line":1,"column":1,"end_line":2,"end_column":2,"#,
+                r#""message":"first defined here"},"#,
+                r#"{"start":17,"end":19,"line":3,"column":3,"end_line":3,"end_column":4,"#,
+                r#""message":"é"}],"#,
+                r#""notes":["one namespace","see \\ docs"],"help":null,"expected":[]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn json_empty_fields() {
+        let src = "fn $main() () {\n";
+        let d = Diagnostic::warning("w", 0..0).with_help("h");
+        assert!(to_json(&d, src, "b.nir").contains(r#""severity":"warning","code":null,"#));
+        assert!(to_json(&d, src, "b.nir").contains(r#""help":"h","#));
+        assert_eq!(
+            to_json(&first(src), src, "b.nir"),
+            concat!(
+                r#"{"file":"b.nir","severity":"error","code":"syntax","#,
+                r#""message":"expected statement or `}`, found the end of the file","#,
+                r#""start":15,"end":15,"line":1,"column":16,"end_line":1,"end_column":16,"#,
+                r#""label":"","secondary":[],"notes":[],"help":null,"#,
+                r#""expected":["statement","`}`"]}"#,
+            )
+        );
+    }
+}
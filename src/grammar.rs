@@ -0,0 +1,716 @@
+use alloc::{borrow::ToOwned, boxed::Box, string::String, vec::Vec};
+
+use logos::Logos;
+
+use crate::ast::{
+    AstNode, CastOp, Field, Function, HeaderConst, Item, Module, Param, StructDef, SysOp, Type,
+};
+use crate::diagnostic::Diagnostic;
+use crate::{
+    reserved, tok, BoxedParser, Expected, Parser, ParserErr, Range, Tok, Token, TokenStream,
+};
+
+/// Decodes a string literal token (quotes included) into its UTF-8 value
+pub fn unescape(lit: &str, span: Range) -> Result<String, ParserErr> {
+    let err = |at: usize, expected: &str| ParserErr {
+        end_idx: span.start + at,
+        expected: [Expected::Label(expected.to_owned())].into(),
+        next: Some((Tok::Str, lit.to_owned())),
+    };
+    let body = &lit[1..lit.len() - 1];
+    let mut bytes = Vec::with_capacity(body.len());
+    let mut chars = body.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        // idx is relative to the body, +1 for the opening quote
+        let at = idx + 1;
+        match chars.next().map(|x| x.1) {
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('"') => bytes.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).map(|x| x.1).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 => bytes.push(b),
+                    _ => return Err(err(at, "two hex digits after \\x")),
+                }
+            }
+            Some('u') => {
+                if chars.next().map(|x| x.1) != Some('{') {
+                    return Err(err(at, "{ after \\u"));
+                }
+                let hex: String = chars
+                    .by_ref()
+                    .map(|x| x.1)
+                    .take_while(|x| *x != '}')
+                    .collect();
+                let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                match c {
+                    Some(c) => {
+                        let mut buf = [0; 4];
+                        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    }
+                    None => return Err(err(at, "unicode scalar value in \\u{...}")),
+                }
+            }
+            _ => return Err(err(at, "escape sequence")),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| err(0, "valid UTF-8 string"))
+}
+
+/// Value of a number token, `None` if it doesn't fit in 64 bits
+pub fn parse_number(lit: &str) -> Option<u64> {
+    if let Some(hex) = lit.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lit.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2).ok()
+    } else {
+        lit.parse().ok()
+    }
+}
+
+pub fn header<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Vec<HeaderConst<'a>>, ParserErr> {
+    tok(Tok::HeaderStart).ignore_then(header_rest())
+}
+
+/// The constants of a header and `--END--`, everything after `--NIR--`
+fn header_rest<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Vec<HeaderConst<'a>>, ParserErr> {
+    let constant = tok(Tok::Caret)
+        .ignore_then(tok(Tok::Ident))
+        .then_ignore(tok(Tok::Equals))
+        .chain(tok(Tok::Str).try_map(unescape))
+        .then_ignore(tok(Tok::Semicolon))
+        .map_range(|x, span| HeaderConst {
+            name: x.0,
+            value: x.1,
+            span,
+        });
+    constant
+        .repeated()
+        .then_ignore(tok(Tok::HeaderEnd))
+        .or(tok(Tok::HeaderEnd).map(|_| Vec::new()))
+}
+
+#[allow(clippy::type_complexity)]
+pub fn path<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Vec<(Range, &'a str)>, ParserErr> {
+    tok(Tok::Ident)
+        .or(tok(Tok::Number))
+        .map_range(|x, r| (r, x))
+        .repeated_sep(tok(Tok::Comma))
+}
+
+pub fn ty<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Type<'a>, ParserErr> {
+    let prim = tok(Tok::Ident).try_map(|x, r| {
+        Type::primitive(x).ok_or_else(|| ParserErr {
+            end_idx: r.start,
+            expected: if x.starts_with('f') {
+                ["f32", "f64"]
+                    .map(|x| Expected::Keyword(x.to_owned()))
+                    .into()
+            } else {
+                [Expected::Label("type".to_owned())].into()
+            },
+            next: Some((Tok::Ident, x.to_owned())),
+        })
+    });
+    tok(Tok::Dollar)
+        .ignore_then(tok(Tok::Ident))
+        .map(Type::Ref)
+        .or(prim)
+        .chain(tok(Tok::Star).repeated().optional())
+        .map(|x| x.1.into_iter().flatten().fold(x.0, |t, _| t.ptr()))
+}
+
+/// Return type of a function or call, `()` for void
+pub fn ret_ty<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Option<Type<'a>>, ParserErr> {
+    tok(Tok::OpenBracket)
+        .chain(tok(Tok::CloseBracket))
+        .map(|_| None)
+        .or(bracketed_ty().map(Some))
+}
+
+/// A type between brackets, as in `add(u32)`
+pub fn bracketed_ty<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Type<'a>, ParserErr> {
+    ty().delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket))
+}
+
+/// Zero or more comma separated `item`s between `open` and `close`
+pub fn list<'t, 'a: 't, O: 't>(
+    item: BoxedParser<'t, &'t [Token<'a>], O, ParserErr>,
+    open: Tok,
+    close: Tok,
+) -> BoxedParser<'t, &'t [Token<'a>], Vec<O>, ParserErr> {
+    tok(open).chain(tok(close)).map(|_| Vec::new()).or(item
+        .repeated_sep(tok(Tok::Comma))
+        .delimited(tok(open), tok(close)))
+}
+
+pub fn atom<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], AstNode<'a>, ParserErr> {
+    let local = tok(Tok::Ampersand)
+        .chain(tok(Tok::Ident))
+        .map_range(|x, r| AstNode::Local(x.1, r));
+    let global = tok(Tok::Dollar)
+        .chain(tok(Tok::Ident))
+        .map_range(|x, r| AstNode::Global(x.1, r));
+    let strref = tok(Tok::Caret)
+        .chain(tok(Tok::Ident))
+        .map_range(|x, r| AstNode::StrRef(x.1, r));
+    let cst = tok(Tok::Bang)
+        .ignore_then(bracketed_ty())
+        .chain(tok(Tok::Number))
+        .map_range(|x, r| AstNode::Const(x.0, x.1, r));
+    let cptroffset = reserved("cptroffset")
+        .ignore_then(ty())
+        .then_ignore(tok(Tok::Comma))
+        .chain(tok(Tok::Number))
+        .map_range(|x, r| AstNode::CPtrOffset(x.0, x.1, r));
+
+    let sptroffset = reserved("sptroffset")
+        .ignore_then(ty())
+        .then_ignore(tok(Tok::Comma))
+        .chain(path())
+        .map_range(|x, r| AstNode::SPtrOffset(x.0, x.1.into_iter().map(|y| y.1).collect(), r));
+    local
+        .or(global)
+        .or(strref)
+        .or(cst)
+        .or(cptroffset)
+        .or(sptroffset)
+        .labelled("operand")
+}
+
+pub fn op<'t, 'a: 't>(
+    standalone: bool,
+) -> BoxedParser<'t, &'t [Token<'a>], AstNode<'a>, ParserErr> {
+    macro_rules! binop {
+        ($x:ident $y:ident) => {
+            let $x = reserved(stringify!($x))
+                .ignore_then(bracketed_ty())
+                .chain(atom().then_ignore(tok(Tok::Comma)).chain(atom()))
+                .map_range(|x, r| AstNode::$y(x.0, Box::new(x.1 .0), Box::new(x.1 .1), r));
+        };
+    }
+
+    macro_rules! unop {
+        ($x:ident $y:ident) => {
+            let $x = reserved(stringify!($x))
+                .ignore_then(bracketed_ty())
+                .chain(atom())
+                .map_range(|x, r| AstNode::$y(x.0, Box::new(x.1), r));
+        };
+    }
+
+    binop!(add Add);
+    binop!(sub Sub);
+    binop!(div Div);
+    binop!(mul Mul);
+    binop!(rem Rem);
+    binop!(and And);
+    binop!(or Or);
+    binop!(xor Xor);
+    binop!(shl Shl);
+    binop!(shr Shr);
+    binop!(eq Eq);
+    binop!(ne Ne);
+    binop!(lt Lt);
+    binop!(le Le);
+    binop!(gt Gt);
+    binop!(ge Ge);
+    unop!(neg Neg);
+    unop!(not Not);
+    let cast = CastOp::ALL
+        .into_iter()
+        .map(|op| {
+            reserved(op.name())
+                .ignore_then(bracketed_ty())
+                .chain(ty())
+                .chain(atom())
+                .map_range(move |x, r| AstNode::Cast(op, x.0 .0, x.0 .1, Box::new(x.1), r))
+        })
+        .reduce(|a, b| a.or(b))
+        .unwrap();
+    let cpy = reserved("cpy")
+        .ignore_then(atom())
+        .map_range(|x, r| AstNode::Cpy(Box::new(x), r));
+    let call = reserved("call")
+        .ignore_then(ret_ty())
+        .chain(atom())
+        .chain(list(
+            ty().chain(atom()),
+            Tok::OpenBracket,
+            Tok::CloseBracket,
+        ))
+        .map_range(|x, r| AstNode::Call(x.0 .0, Box::new(x.0 .1), x.1, r));
+    let ret = reserved("ret")
+        .ignore_then(bracketed_ty())
+        .chain(atom())
+        .map_range(|x, r| AstNode::Ret(x.0, Box::new(x.1), r))
+        .or(reserved("ret").map_range(|_, r| AstNode::RetVoid(r)));
+    let stalloc = reserved("stalloc")
+        .ignore_then(ty())
+        .then_ignore(reserved("times"))
+        .chain(atom())
+        .map_range(|x, r| AstNode::Stalloc(x.0, Box::new(x.1), r));
+    let halloc = reserved("halloc")
+        .ignore_then(ty())
+        .then_ignore(reserved("times"))
+        .chain(atom())
+        .map_range(|x, r| AstNode::Halloc(x.0, Box::new(x.1), r));
+    let hfree = reserved("hfree")
+        .ignore_then(atom())
+        .map_range(|x, r| AstNode::Hfree(Box::new(x), r));
+    let hrealloc = reserved("hrealloc")
+        .ignore_then(bracketed_ty())
+        .then_ignore(reserved("ptr"))
+        .chain(atom())
+        .then_ignore(reserved("times"))
+        .chain(atom())
+        .map_range(|x, r| AstNode::Hrealloc(x.0 .0, Box::new(x.0 .1), Box::new(x.1), r));
+    let ptroffset = reserved("ptroffset")
+        .ignore_then(bracketed_ty())
+        .then_ignore(reserved("ptr"))
+        .chain(atom().chain(atom()))
+        .map_range(|x, r| AstNode::Ptroffset(x.0, Box::new(x.1 .0), Box::new(x.1 .1), r));
+    let load = reserved("load")
+        .ignore_then(bracketed_ty())
+        .then_ignore(reserved("ptr"))
+        .chain(atom())
+        .map_range(|x, r| AstNode::Load(x.0, Box::new(x.1), r));
+    let store = reserved("store")
+        .ignore_then(bracketed_ty())
+        .then_ignore(reserved("ptr"))
+        .chain(atom().then_ignore(tok(Tok::Comma)).chain(atom()))
+        .map_range(|x, r| AstNode::Store(x.0, Box::new(x.1 .0), Box::new(x.1 .1), r));
+    let dbg = reserved("dbg")
+        .ignore_then(bracketed_ty())
+        .chain(atom())
+        .map_range(|x, r| AstNode::Dbg(x.0, Box::new(x.1), r));
+    let jmp = reserved("jmp").ignore_then(label()).map_range(AstNode::Jmp);
+    let br = reserved("br")
+        .ignore_then(bracketed_ty())
+        .chain(atom())
+        .then_ignore(tok(Tok::Comma))
+        .chain(label())
+        .then_ignore(tok(Tok::Comma))
+        .chain(label())
+        .map_range(|x, r| AstNode::Br(x.0 .0 .0, Box::new(x.0 .0 .1), x.0 .1, x.1, r));
+    let sys = SysOp::ALL
+        .into_iter()
+        .map(|op| {
+            let name = reserved("sys").ignore_then(reserved(op.name()));
+            if op.params().is_empty() {
+                name.map_range(move |_, r| AstNode::Sys(op, Vec::new(), r))
+            } else {
+                name.ignore_then(atom().repeated_sep(tok(Tok::Comma)))
+                    .map_range(move |x, r| AstNode::Sys(op, x, r))
+            }
+        })
+        .reduce(|a, b| a.or(b))
+        .unwrap();
+    if !standalone {
+        call.or(sys)
+            .or(stalloc)
+            .or(halloc)
+            .or(hrealloc)
+            .or(ptroffset)
+            .or(load)
+            .or(add)
+            .or(sub)
+            .or(div)
+            .or(mul)
+            .or(rem)
+            .or(and)
+            .or(or)
+            .or(xor)
+            .or(shl)
+            .or(shr)
+            .or(eq)
+            .or(ne)
+            .or(lt)
+            .or(le)
+            .or(gt)
+            .or(ge)
+            .or(neg)
+            .or(not)
+            .or(cast)
+            .or(cpy)
+            .labelled("instruction")
+    } else {
+        call.or(sys)
+            .or(ret)
+            .or(store)
+            .or(hfree)
+            .or(dbg)
+            .or(jmp)
+            .or(br)
+            .labelled("instruction")
+    }
+}
+
+pub fn stmt<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], AstNode<'a>, ParserErr> {
+    let justop = op(true);
+    let var = atom().check(|x| {
+        if x.is_var() {
+            None
+        } else {
+            Some("variable".to_owned())
+        }
+    });
+    let eq = var
+        .then_ignore(tok(Tok::Equals))
+        .chain(op(false))
+        .map_range(|x, r| AstNode::Equals(Box::new(x.0), Box::new(x.1), r));
+    justop
+        .or(eq)
+        .or(label().map_range(AstNode::Label))
+        .labelled("statement")
+}
+
+/// A statement, or [`AstNode::Err`] covering everything up to the start of the next
+/// statement, closing brace or item if it doesn't parse
+pub fn stmt_or_err<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], AstNode<'a>, ParserErr> {
+    stmt().recover(
+        |prev, rest| {
+            matches!(rest.first(), Some((Tok::CloseBrace, ..)))
+                || starts_item(rest)
+                // The failed statement starts like one too, skip at least it
+                || prev.is_some() && starts_stmt(prev, rest)
+        },
+        AstNode::Err,
+    )
+}
+
+/// Whether a statement begins at `rest`, with `prev` before it. Only looks at the first few
+/// tokens, so a statement with mistakes of its own still stops the skipping before it
+fn starts_stmt(prev: Option<&Token>, rest: &[Token]) -> bool {
+    match rest {
+        [(Tok::Ampersand, ..), (Tok::Ident, ..), (Tok::Equals, ..), ..] => true,
+        // Jump targets look like labels but come after `jmp` or a comma, or before a comma
+        [(Tok::Dot, ..), (Tok::Ident, ..), (Tok::Comma, ..), ..] => false,
+        [(Tok::Dot, ..), (Tok::Ident, ..), ..] => {
+            !matches!(prev, Some((Tok::Comma, ..) | (Tok::Ident, _, "jmp")))
+        }
+        [(Tok::Ident, _, "ret" | "jmp" | "br" | "store" | "hfree" | "dbg"), ..] => true,
+        // These are also the instruction of an assignment
+        [(Tok::Ident, _, "call" | "sys"), ..] => !matches!(prev, Some((Tok::Equals, ..))),
+        _ => false,
+    }
+}
+
+/// `.name`, both where a label is defined and where it is jumped to
+pub fn label<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], &'a str, ParserErr> {
+    tok(Tok::Dot).ignore_then(tok(Tok::Ident))
+}
+
+pub fn function<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Function<'a>, ParserErr> {
+    let param = ty().chain(tok(Tok::Ident)).map_range(|x, span| Param {
+        ty: x.0,
+        name: x.1,
+        span,
+    });
+    let body = tok(Tok::OpenBrace)
+        .chain(tok(Tok::CloseBrace))
+        .map(|_| Vec::new())
+        .or(stmt_or_err()
+            .repeated()
+            .delimited(tok(Tok::OpenBrace), tok(Tok::CloseBrace)));
+    let ret = tok(Tok::OpenBracket)
+        .chain(tok(Tok::CloseBracket))
+        .map_range(|_, span| (None, span))
+        .or(ty()
+            .map_range(|x, span| (Some(x), span))
+            .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)));
+    reserved("fn")
+        .ignore_then(tok(Tok::Dollar))
+        .ignore_then(tok(Tok::Ident))
+        .chain(ret)
+        .chain(list(param, Tok::OpenBracket, Tok::CloseBracket))
+        .chain(body)
+        .map_range(|x, span| Function {
+            name: x.0 .0 .0,
+            ret: x.0 .0 .1 .0,
+            ret_span: x.0 .0 .1 .1,
+            params: x.0 .1,
+            body: x.1,
+            span,
+        })
+}
+
+pub fn structdef<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], StructDef<'a>, ParserErr> {
+    let field = tok(Tok::Ident)
+        .then_ignore(tok(Tok::Equals))
+        .chain(ty())
+        .map_range(|x, span| Field {
+            name: Some(x.0),
+            ty: x.1,
+            span,
+        })
+        .or(ty().map_range(|ty, span| Field {
+            name: None,
+            ty,
+            span,
+        }));
+    reserved("packed")
+        .optional()
+        .then_ignore(reserved("struct"))
+        .then_ignore(tok(Tok::Dollar))
+        .chain(tok(Tok::Ident))
+        .chain(
+            list(field, Tok::OpenBrace, Tok::CloseBrace).check(|fields| {
+                if fields.iter().all(|f| f.name.is_some())
+                    || fields.iter().all(|f| f.name.is_none())
+                {
+                    None
+                } else {
+                    Some("fields that are all named or all positional".to_owned())
+                }
+            }),
+        )
+        .map_range(|x, span| StructDef {
+            name: x.0 .1,
+            fields: x.1,
+            packed: x.0 .0.is_some(),
+            span,
+        })
+}
+
+pub fn item<'t, 'a: 't>() -> BoxedParser<'t, &'t [Token<'a>], Item<'a>, ParserErr> {
+    function()
+        .map(Item::Function)
+        .or(structdef().map(Item::Struct))
+}
+
+/// Whether `rest` starts with a keyword that begins an item
+fn starts_item(rest: &[Token]) -> bool {
+    matches!(
+        rest.first(),
+        Some((Tok::Ident, _, "fn" | "struct" | "packed"))
+    )
+}
+
+/// A module and the errors of the parts that were skipped to get past them
+pub fn module<'t, 'a: 't>(
+) -> BoxedParser<'t, &'t [Token<'a>], (Module<'a>, Vec<ParserErr>), ParserErr> {
+    let header = tok(Tok::HeaderStart).ignore_then(
+        header_rest()
+            .map(Ok)
+            .recover(|_, rest| starts_item(rest), |e, _| Err(e)),
+    );
+    let next = item();
+    let items = item().map(Ok).recover(
+        move |_, rest| starts_item(rest) && next.parse(rest).is_ok(),
+        |e, _| Err(e),
+    );
+    header
+        .optional()
+        .chain(items.repeated().optional())
+        .map(|(header, items)| {
+            let mut errors = Vec::new();
+            let header = match header {
+                Some(Ok(header)) => header,
+                Some(Err(e)) => {
+                    errors.push(e);
+                    Vec::new()
+                }
+                None => Vec::new(),
+            };
+            let items = items
+                .into_iter()
+                .flatten()
+                .filter_map(|x| x.map_err(|e| errors.push(e)).ok())
+                .collect();
+            (Module { header, items }, errors)
+        })
+        .eoi()
+}
+
+/// Lexes and parses a whole NIR file
+pub fn parse_module(src: &str) -> Result<Module<'_>, Vec<Diagnostic>> {
+    match parse_module_partial(src) {
+        (Some(module), diags) if diags.is_empty() => Ok(module),
+        (_, diags) => Err(diags),
+    }
+}
+
+/// Like [`parse_module`], but goes on after mistakes and reports all of them. Statements that
+/// don't parse become [`AstNode::Err`], items that don't parse are left out. `None` if
+/// nothing could be parsed at all
+pub fn parse_module_partial(src: &str) -> (Option<Module<'_>>, Vec<Diagnostic>) {
+    let ts = TokenStream::new(Tok::lexer(src));
+    let (module, mut errors) = match module().parse(ts.slice()) {
+        Ok((_, x, _)) => x,
+        Err(e) => return (None, alloc::vec![Diagnostic::from(e)]),
+    };
+    for item in &module.items {
+        if let Item::Function(f) = item {
+            errors.extend(f.body.iter().filter_map(|node| match node {
+                AstNode::Err(e, _) => Some(e.clone()),
+                _ => None,
+            }));
+        }
+    }
+    let mut diags: Vec<Diagnostic> = errors.into_iter().map(Diagnostic::from).collect();
+    diags.sort_by_key(|d| d.span.start);
+    (Some(module), diags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn messages(src: &str) -> Vec<String> {
+        let (_, diags) = parse_module_partial(src);
+        diags.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn empty_modules() {
+        assert!(parse_module("").unwrap().items.is_empty());
+        assert!(parse_module("--NIR--\n^A=\"x\";\n--END--\n").is_ok());
+    }
+
+    #[test]
+    fn header_semicolons_and_crlf() {
+        let m = parse_module("--NIR--\r\n^A=\"x\"; ^B=\"y\";\r\n--END-- ; comment\r\n").unwrap();
+        let names: Vec<&str> = m.header.iter().map(|c| c.name).collect();
+        assert_eq!(names, ["A", "B"]);
+    }
+
+    #[test]
+    fn escapes() {
+        let unescape = |lit: &str| unescape(lit, 10..10 + lit.len());
+        assert_eq!(unescape(r#""a\tb\n\r\0\\\"""#).unwrap(), "a\tb\n\r\0\\\"");
+        assert_eq!(unescape(r#""\x41\xc3\xa9""#).unwrap(), "Aé");
+        assert_eq!(unescape(r#""\u{1F600}\u{e9}""#).unwrap(), "😀é");
+        let expected = |lit: &str| {
+            let e = unescape(lit).unwrap_err();
+            (
+                e.end_idx,
+                e.expected.into_iter().next().unwrap().to_string(),
+            )
+        };
+        assert_eq!(expected(r#""ab\q""#), (13, "escape sequence".into()));
+        assert_eq!(
+            expected(r#""\x4""#),
+            (11, r"two hex digits after \x".into())
+        );
+        assert_eq!(
+            expected(r#""\xzz""#),
+            (11, r"two hex digits after \x".into())
+        );
+        assert_eq!(expected(r#""\u41""#), (11, r"{ after \u".into()));
+        assert_eq!(
+            expected(r#""\u{D800}""#),
+            (11, r"unicode scalar value in \u{...}".into())
+        );
+        assert_eq!(expected(r#""\xff""#), (10, "valid UTF-8 string".into()));
+    }
+
+    #[test]
+    fn recovers_after_errors() {
+        let src = "fn $f(u32) (u32 a) {
+    &x = add(u32) &a !(u32)1
+    &y = add(u32) &a, !(u32)1
+    &z = bogus &x
+    ret(u32) &a
+}
+fn $broken(u32 (u32 a) {
+    ret(u32) &a
+}
+fn $main() () {
+    jmpp .end
+.end
+}
+";
+        assert_eq!(
+            messages(src),
+            [
+                "expected `,`, found `!`",
+                "expected instruction, found `bogus`",
+                "expected `)`, found `(`",
+                "expected statement, found `jmpp`",
+            ]
+        );
+        let (module, _) = parse_module_partial(src);
+        let names: Vec<&str> = module
+            .unwrap()
+            .items
+            .iter()
+            .filter_map(|x| match x {
+                Item::Function(f) => Some(f.name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, ["f", "main"]);
+    }
+
+    #[test]
+    fn recovers_after_adjacent_errors() {
+        let src = "fn $main() () {
+    &y = bogus
+    &z = add(u8) !(u8)1 !(u8)2
+    &w = what
+    br(bool) &y .a, .b
+.a
+    ret
+}
+";
+        assert_eq!(
+            messages(src),
+            [
+                "expected instruction, found `bogus`",
+                "expected `,`, found `!`",
+                "expected instruction, found `what`",
+                "expected `,`, found `.`",
+            ]
+        );
+        let (module, _) = parse_module_partial(src);
+        let Item::Function(f) = &module.unwrap().items[0] else {
+            panic!("not a function")
+        };
+        // the jump targets of the broken `br` don't count as labels
+        assert!(matches!(
+            f.body[..],
+            [
+                AstNode::Err(..),
+                AstNode::Err(..),
+                AstNode::Err(..),
+                AstNode::Err(..),
+                AstNode::Label(..),
+                AstNode::RetVoid(..)
+            ]
+        ));
+    }
+
+    #[test]
+    fn expected_sets() {
+        assert_eq!(
+            messages("fn $main() () {\n"),
+            ["expected statement or `}`, found the end of the file"]
+        );
+        assert_eq!(
+            messages("fn $main() () {\n    &x = add(u32) !(u32)1,\n}\n"),
+            ["expected operand, found `}`"]
+        );
+        assert_eq!(
+            messages("fn $main(f16) () {}"),
+            ["expected `f32`, `f64`, `$` or `)`, found `f16`"]
+        );
+    }
+
+    #[test]
+    fn errors_at_the_end_point_after_the_last_token() {
+        let src = "fn $main() () {  ";
+        let (_, diags) = parse_module_partial(src);
+        assert_eq!(diags[0].span, 15..15);
+    }
+}
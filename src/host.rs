@@ -0,0 +1,260 @@
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+
+use crate::ast::{Type, TypeSize};
+use crate::interp::Value;
+
+/// A Rust type that can be passed to and from NIR
+pub trait HostValue: Sized {
+    fn ty() -> Type<'static>;
+    /// `None` if `v` isn't a value of `Self::ty()`
+    fn from_value(v: Value) -> Option<Self>;
+    fn into_value(self) -> Value;
+}
+
+macro_rules! host_int {
+    ($t:ty, $variant:ident, $size:ident) => {
+        impl HostValue for $t {
+            fn ty() -> Type<'static> {
+                Type::$variant(TypeSize::$size)
+            }
+
+            fn from_value(v: Value) -> Option<Self> {
+                match v {
+                    Value::$variant(x, TypeSize::$size) => Some(x as $t),
+                    _ => None,
+                }
+            }
+
+            fn into_value(self) -> Value {
+                Value::$variant(self as _, TypeSize::$size)
+            }
+        }
+    };
+}
+
+host_int!(u8, U, _8);
+host_int!(u16, U, _16);
+host_int!(u32, U, _32);
+host_int!(u64, U, _64);
+host_int!(i8, I, _8);
+host_int!(i16, I, _16);
+host_int!(i32, I, _32);
+host_int!(i64, I, _64);
+
+macro_rules! host_simple {
+    ($t:ty, $ty:expr, $variant:ident) => {
+        impl HostValue for $t {
+            fn ty() -> Type<'static> {
+                $ty
+            }
+
+            fn from_value(v: Value) -> Option<Self> {
+                match v {
+                    Value::$variant(x) => Some(x),
+                    _ => None,
+                }
+            }
+
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+        }
+    };
+}
+
+host_simple!(f32, Type::F(TypeSize::_32), F32);
+host_simple!(f64, Type::F(TypeSize::_64), F64);
+host_simple!(bool, Type::Bool, Bool);
+
+/// What a host function returns, `()` for void functions
+pub trait HostRet {
+    fn ty() -> Option<Type<'static>>;
+    fn into_value(self) -> Option<Value>;
+}
+
+impl HostRet for () {
+    fn ty() -> Option<Type<'static>> {
+        None
+    }
+
+    fn into_value(self) -> Option<Value> {
+        None
+    }
+}
+
+impl<T: HostValue> HostRet for T {
+    fn ty() -> Option<Type<'static>> {
+        Some(T::ty())
+    }
+
+    fn into_value(self) -> Option<Value> {
+        Some(HostValue::into_value(self))
+    }
+}
+
+/// A Rust closure NIR can call, `Args` is the tuple of its parameter types
+pub trait HostFn<Args> {
+    fn params() -> Vec<Type<'static>>;
+    fn ret() -> Option<Type<'static>>;
+    /// `None` if the arguments don't match the parameters
+    fn call(&mut self, args: &[Value]) -> Option<Option<Value>>;
+}
+
+macro_rules! host_fn {
+    ($($a:ident),*) => {
+        impl<F, R, $($a),*> HostFn<($($a,)*)> for F
+        where
+            F: FnMut($($a),*) -> R,
+            R: HostRet,
+            $($a: HostValue),*
+        {
+            fn params() -> Vec<Type<'static>> {
+                vec![$($a::ty()),*]
+            }
+
+            fn ret() -> Option<Type<'static>> {
+                R::ty()
+            }
+
+            #[allow(non_snake_case)]
+            fn call(&mut self, args: &[Value]) -> Option<Option<Value>> {
+                let [$($a),*] = args else {
+                    return None;
+                };
+                Some(self($($a::from_value(*$a)?),*).into_value())
+            }
+        }
+    };
+}
+
+host_fn!();
+host_fn!(A);
+host_fn!(A, B);
+host_fn!(A, B, C);
+host_fn!(A, B, C, D);
+host_fn!(A, B, C, D, E);
+host_fn!(A, B, C, D, E, G);
+
+/// A host function with its arguments converted, `None` if they don't match its parameters
+type Call = dyn FnMut(&[Value]) -> Option<Option<Value>>;
+
+/// A registered host function with its signature
+pub struct Host {
+    pub params: Vec<Type<'static>>,
+    pub ret: Option<Type<'static>>,
+    f: Box<Call>,
+}
+
+impl Host {
+    pub fn new<Args, F: HostFn<Args> + 'static>(mut f: F) -> Self {
+        Host {
+            params: F::params(),
+            ret: F::ret(),
+            f: Box::new(move |args| f.call(args)),
+        }
+    }
+
+    pub fn call(&mut self, args: &[Value]) -> Option<Option<Value>> {
+        (self.f)(args)
+    }
+
+    /// The signature as it would be written in NIR, e.g. `(u32) (u32, bool)`
+    pub fn signature(&self) -> String {
+        signature(self.ret.as_ref(), self.params.iter())
+    }
+}
+
+pub(crate) fn signature<'t, 'a: 't>(
+    ret: Option<&Type>,
+    params: impl Iterator<Item = &'t Type<'a>>,
+) -> String {
+    let ret = ret.map(|t| format!("{t}")).unwrap_or_default();
+    let params: Vec<String> = params.map(|t| format!("{t}")).collect();
+    format!("({ret}) ({})", params.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{Interpreter, TrapKind};
+    use crate::parse_module;
+    use crate::resolve::resolve_module_with;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[test]
+    fn signatures() {
+        let h = Host::new(|a: u32, b: bool| if b { a as i64 } else { 0 });
+        assert_eq!(h.signature(), "(s64) (u32, bool)");
+        assert_eq!(Host::new(|| ()).signature(), "() ()");
+        assert_eq!(
+            Host::new(|_: f32, _: u8| 1.0f64).signature(),
+            "(f64) (f32, u8)"
+        );
+    }
+
+    #[test]
+    fn converts_arguments() {
+        let mut h = Host::new(|a: u32, b: u32| a + b);
+        let (a, b) = (Value::U(2, TypeSize::_32), Value::U(3, TypeSize::_32));
+        assert_eq!(h.call(&[a, b]), Some(Some(Value::U(5, TypeSize::_32))));
+        assert_eq!(h.call(&[a]), None);
+        assert_eq!(h.call(&[a, Value::Bool(true)]), None);
+    }
+
+    #[test]
+    fn called_from_nir() {
+        let src = "fn $main(u32) () {
+    &x = call(u32) $add (u32 !(u32)2, u32 !(u32)3)
+    call() $log (u32 &x)
+    ret(u32) &x
+}
+";
+        let module = parse_module(src).unwrap();
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut i = Interpreter::new(&module, String::new());
+        i.register("$add", |a: u32, b: u32| a + b);
+        let log = seen.clone();
+        i.register("log", move |x: u32| log.borrow_mut().push(x));
+        assert!(resolve_module_with(&module, &i.host_names()).1.is_empty());
+        assert_eq!(i.run_main().unwrap(), Some(Value::U(5, TypeSize::_32)));
+        assert_eq!(*seen.borrow(), [5]);
+    }
+
+    #[test]
+    fn module_functions_shadow_hosts() {
+        let src = "fn $f(u8) () {
+    ret(u8) !(u8)1
+}
+fn $main(u8) () {
+    &x = call(u8) $f ()
+    ret(u8) &x
+}
+";
+        let module = parse_module(src).unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.register("f", || 2u8);
+        assert_eq!(i.run_main().unwrap(), Some(Value::U(1, TypeSize::_8)));
+    }
+
+    #[test]
+    fn mismatched_calls() {
+        let src = "fn $main() () {
+    &x = call(u64) $add (u32 !(u32)2, u32 !(u32)3)
+}
+";
+        let module = parse_module(src).unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.register("add", |a: u32, b: u32| a + b);
+        let trap = i.run_main().unwrap_err();
+        assert_eq!(
+            trap.kind,
+            TrapKind::HostMismatch {
+                name: "add".into(),
+                expected: "(u32) (u32, u32)".into(),
+                found: "as (u64) (u32, u32)".into(),
+            }
+        );
+        assert_eq!(&src[trap.span], "call(u64) $add (u32 !(u32)2, u32 !(u32)3)");
+    }
+}
@@ -0,0 +1,1519 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use crate::ast::{AstNode, BinOp, CastOp, Function, Item, Module, SysOp, Type, TypeSize, UnOp};
+use crate::diagnostic::Diagnostic;
+use crate::grammar::parse_number;
+use crate::host::{signature, Host, HostFn};
+use crate::layout::{compute_layouts, Layouts, Target};
+use crate::sys::System;
+use crate::Range;
+
+/// Address of the first header constant, nothing lives below it so 0 is never valid
+pub const STATIC_BASE: u64 = 0x1000;
+/// Address of the bottom of the stack
+pub const STACK_BASE: u64 = 0x1000_0000;
+/// Address of the first heap block
+pub const HEAP_BASE: u64 = 0x1000_0000_0000;
+
+/// A resource limit of the interpreter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Fuel,
+    StackBytes,
+    CallDepth,
+    OutputBytes,
+}
+
+/// Bounds on what a program may use, `None` is unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Number of instructions executed, labels don't count
+    pub fuel: Option<u64>,
+    pub stack_bytes: u64,
+    /// Bytes of live heap blocks, `sys alloc` produces null past it
+    pub heap_bytes: u64,
+    /// Calls active at once, including `$main`. Every level also uses stack of the host
+    pub call_depth: usize,
+    /// Bytes of `dbg` output, with a newline after every value
+    pub output_bytes: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: None,
+            stack_bytes: 1 << 20,
+            heap_bytes: 1 << 26,
+            call_depth: 256,
+            output_bytes: None,
+        }
+    }
+}
+
+/// Index of an allocation, pointers into it carry it as their provenance
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AllocId(pub usize);
+
+/// A runtime value, integers carry their type so they can be printed and converted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I(i64, TypeSize),
+    U(u64, TypeSize),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    /// Address and the allocation it was derived from, if known
+    Ptr(u64, Option<AllocId>),
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits == 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+fn sext(v: u64, bits: u32) -> i64 {
+    ((v << (64 - bits)) as i64) >> (64 - bits)
+}
+
+impl Value {
+    /// Raw bits, zero extended to 64
+    pub fn to_bits(self) -> u64 {
+        match self {
+            Value::I(v, s) => v as u64 & mask(s.bits()),
+            Value::U(v, _) => v,
+            Value::F32(f) => u64::from(f.to_bits()),
+            Value::F64(f) => f.to_bits(),
+            Value::Bool(b) => u64::from(b),
+            Value::Ptr(p, _) => p,
+        }
+    }
+
+    /// Reinterprets `bits` as a value of `ty`, `None` for structs
+    pub fn from_bits(bits: u64, ty: &Type) -> Option<Value> {
+        Some(match ty {
+            Type::I(s) => Value::I(sext(bits & mask(s.bits()), s.bits()), *s),
+            Type::U(s) => Value::U(bits & mask(s.bits()), *s),
+            Type::F(TypeSize::_32) => Value::F32(f32::from_bits(bits as u32)),
+            Type::F(_) => Value::F64(f64::from_bits(bits)),
+            Type::Bool => Value::Bool(bits & 1 != 0),
+            Type::Ptr(_) => Value::Ptr(bits, None),
+            Type::Ref(_) => return None,
+        })
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Value::F32(f) => f64::from(f),
+            Value::F64(f) => f,
+            _ => 0.0,
+        }
+    }
+}
+
+impl core::fmt::Display for Value {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Value::I(v, _) => write!(f, "{v}"),
+            Value::U(v, _) => write!(f, "{v}"),
+            Value::F32(v) => write!(f, "{v}"),
+            Value::F64(v) => write!(f, "{v}"),
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::Ptr(v, _) => write!(f, "0x{v:x}"),
+        }
+    }
+}
+
+/// Where `dbg` output goes
+pub trait Output {
+    fn dbg(&mut self, value: Value);
+}
+
+/// Collects `dbg` output one value per line
+impl Output for String {
+    fn dbg(&mut self, value: Value) {
+        self.push_str(&value.to_string());
+        self.push('\n');
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    DivideByZero,
+    /// Signed division of the smallest value by -1
+    DivideOverflow,
+    InvalidAddress(u64),
+    WriteToConstant(u64),
+    /// Access outside of the allocation the pointer was derived from
+    OutOfBounds(u64),
+    UninitializedRead(u64),
+    /// Access through a pointer to a stack allocation of a function that returned
+    DanglingPointer(u64),
+    UseAfterFree(u64),
+    DoubleFree(u64),
+    /// Free of an address that isn't the start of a heap block
+    InvalidFree(u64),
+    /// `sys exit` with an exit code
+    Exit(i32),
+    /// Load at a type different from the last store to the address
+    TypeMismatch {
+        addr: u64,
+        loaded: String,
+        stored: Option<String>,
+    },
+    LimitExceeded(Limit),
+    UndefinedFunction(String),
+    /// Call of a host function that doesn't match its signature
+    HostMismatch {
+        name: String,
+        expected: String,
+        /// How it was called, e.g. `as () (u64)`
+        found: String,
+    },
+    Unsupported(String),
+}
+
+impl core::fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TrapKind::DivideByZero => write!(f, "division by zero"),
+            TrapKind::DivideOverflow => write!(f, "signed division overflow"),
+            TrapKind::InvalidAddress(a) => write!(f, "access to invalid address 0x{a:x}"),
+            TrapKind::WriteToConstant(a) => write!(f, "write to constant memory at 0x{a:x}"),
+            TrapKind::OutOfBounds(a) => write!(f, "out of bounds access at 0x{a:x}"),
+            TrapKind::UninitializedRead(a) => write!(f, "read of uninitialized memory at 0x{a:x}"),
+            TrapKind::DanglingPointer(a) => {
+                write!(
+                    f,
+                    "access at 0x{a:x} to the stack of a function that returned"
+                )
+            }
+            TrapKind::UseAfterFree(a) => write!(f, "access at 0x{a:x} to freed memory"),
+            TrapKind::InvalidFree(a) => write!(f, "free of 0x{a:x}, which isn't a heap block"),
+            TrapKind::DoubleFree(a) => write!(f, "free of 0x{a:x}, which was already freed"),
+            TrapKind::Exit(code) => write!(f, "exited with code {code}"),
+            TrapKind::TypeMismatch {
+                addr,
+                loaded,
+                stored: Some(stored),
+            } => write!(
+                f,
+                "load of {loaded} at 0x{addr:x}, which was stored as {stored}"
+            ),
+            TrapKind::TypeMismatch { addr, loaded, .. } => write!(
+                f,
+                "load of {loaded} at 0x{addr:x}, which is part of a value stored at another address"
+            ),
+            TrapKind::LimitExceeded(l) => {
+                let what = match l {
+                    Limit::Fuel => "instruction",
+                    Limit::StackBytes => "stack size",
+                    Limit::CallDepth => "call depth",
+                    Limit::OutputBytes => "output size",
+                };
+                write!(f, "{what} limit exceeded")
+            }
+            TrapKind::UndefinedFunction(name) => write!(f, "call to undefined function `${name}`"),
+            TrapKind::HostMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`${name}` is a host function with signature {expected}, but is called {found}"
+            ),
+            TrapKind::Unsupported(what) => write!(f, "unsupported: {what}"),
+        }
+    }
+}
+
+/// A runtime error, `span` is the instruction that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub span: Range,
+}
+
+impl From<Trap> for Diagnostic {
+    fn from(t: Trap) -> Self {
+        Diagnostic::error(format!("{}", t.kind), t.span).with_code("trap")
+    }
+}
+
+/// Indices of `addr..addr + len` in a region of `size` bytes starting at `base`
+fn range(base: u64, size: usize, addr: u64, len: u64) -> Option<core::ops::Range<usize>> {
+    let start = addr.checked_sub(base)?;
+    let end = start.checked_add(len)?;
+    (end <= size as u64).then_some(start as usize..end as usize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AllocKind {
+    Static,
+    Stack,
+    Heap,
+}
+
+struct Allocation {
+    base: u64,
+    size: u64,
+    kind: AllocKind,
+    live: bool,
+}
+
+/// The last store to an address
+struct Stored<'a> {
+    ty: Type<'a>,
+    size: u64,
+    alloc: Option<AllocId>,
+}
+
+/// Memory from `sys alloc`
+struct Block {
+    bytes: Vec<u8>,
+    /// Whether each byte was stored to
+    init: Vec<bool>,
+    /// The instruction that allocated it
+    span: Range,
+}
+
+/// A heap block that is still allocated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leak {
+    pub addr: u64,
+    pub size: u64,
+    /// The instruction that allocated it
+    pub span: Range,
+}
+
+impl From<Leak> for Diagnostic {
+    fn from(l: Leak) -> Self {
+        Diagnostic::warning(
+            format!("{} bytes allocated here are never freed", l.size),
+            l.span,
+        )
+        .with_code("leak")
+        .with_help("free it with `hfree` once it's no longer used")
+    }
+}
+
+struct Memory<'a> {
+    statics: Vec<u8>,
+    stack: Vec<u8>,
+    /// Whether each byte of `stack` was stored to since it was allocated
+    init: Vec<bool>,
+    /// Live heap blocks by address
+    heap: BTreeMap<u64, Block>,
+    /// Where the next heap block goes, heap addresses are never reused
+    heap_top: u64,
+    heap_used: u64,
+    /// Stores by address, they never overlap
+    stores: BTreeMap<u64, Stored<'a>>,
+    allocations: Vec<Allocation>,
+    /// Offset of the top of the stack into `stack`
+    sp: u64,
+}
+
+impl<'a> Memory<'a> {
+    fn new() -> Self {
+        Memory {
+            statics: Vec::new(),
+            stack: Vec::new(),
+            init: Vec::new(),
+            heap: BTreeMap::new(),
+            heap_top: HEAP_BASE,
+            heap_used: 0,
+            stores: BTreeMap::new(),
+            allocations: Vec::new(),
+            sp: 0,
+        }
+    }
+
+    /// Stack or heap memory `addr` may be in, with its address and which bytes are initialized
+    fn region(&self, addr: u64) -> Option<(u64, &[u8], &[bool])> {
+        if (STACK_BASE..HEAP_BASE).contains(&addr) {
+            return Some((STACK_BASE, &self.stack, &self.init));
+        }
+        let (base, b) = self.heap.range(..=addr).next_back()?;
+        Some((*base, &b.bytes, &b.init))
+    }
+
+    fn region_mut(&mut self, addr: u64) -> Option<(u64, &mut [u8], &mut [bool])> {
+        if (STACK_BASE..HEAP_BASE).contains(&addr) {
+            return Some((STACK_BASE, &mut self.stack, &mut self.init));
+        }
+        let (base, b) = self.heap.range_mut(..=addr).next_back()?;
+        Some((*base, &mut b.bytes, &mut b.init))
+    }
+
+    /// Bytes `addr..addr + len`, with whether they are initialized unless they are constant
+    fn bytes(&self, addr: u64, len: u64) -> Result<(&[u8], Option<&[bool]>), TrapKind> {
+        if let Some(r) = range(STATIC_BASE, self.statics.len(), addr, len) {
+            return Ok((&self.statics[r], None));
+        }
+        self.region(addr)
+            .and_then(|(base, mem, init)| {
+                let r = range(base, mem.len(), addr, len)?;
+                Some((&mem[r.clone()], Some(&init[r])))
+            })
+            .ok_or(TrapKind::InvalidAddress(addr))
+    }
+
+    fn bytes_mut(&mut self, addr: u64, len: u64) -> Result<(&mut [u8], &mut [bool]), TrapKind> {
+        if range(STATIC_BASE, self.statics.len(), addr, 1).is_some() {
+            return Err(TrapKind::WriteToConstant(addr));
+        }
+        self.region_mut(addr)
+            .and_then(|(base, mem, init)| {
+                let r = range(base, mem.len(), addr, len)?;
+                Some((&mut mem[r.clone()], &mut init[r]))
+            })
+            .ok_or(TrapKind::InvalidAddress(addr))
+    }
+
+    fn add_static(&mut self, bytes: &[u8]) -> (u64, AllocId) {
+        let base = STATIC_BASE + self.statics.len() as u64;
+        self.statics.extend_from_slice(bytes);
+        self.allocations.push(Allocation {
+            base,
+            size: bytes.len() as u64,
+            kind: AllocKind::Static,
+            live: true,
+        });
+        (base, AllocId(self.allocations.len() - 1))
+    }
+
+    /// Reserves `size` bytes aligned to `align` on top of the stack
+    fn alloc(&mut self, size: u64, align: u64, max: u64) -> Result<(u64, AllocId), TrapKind> {
+        let start = self.sp.div_ceil(align) * align;
+        let end = start
+            .checked_add(size)
+            .filter(|x| *x <= max && STACK_BASE + x <= HEAP_BASE)
+            .ok_or(TrapKind::LimitExceeded(Limit::StackBytes))?;
+        if end as usize > self.stack.len() {
+            self.stack.resize(end as usize, 0);
+            self.init.resize(end as usize, false);
+        }
+        self.init[start as usize..end as usize].fill(false);
+        self.forget_stores(STACK_BASE + start, size);
+        self.sp = end;
+        self.allocations.push(Allocation {
+            base: STACK_BASE + start,
+            size,
+            kind: AllocKind::Stack,
+            live: true,
+        });
+        Ok((STACK_BASE + start, AllocId(self.allocations.len() - 1)))
+    }
+
+    /// Pops the stack back to `sp`, the stack allocations from `first` on die
+    fn release(&mut self, sp: u64, first: usize) {
+        self.sp = sp;
+        for a in &mut self.allocations[first..] {
+            if a.kind == AllocKind::Stack {
+                a.live = false;
+            }
+        }
+    }
+
+    /// A new heap block, `None` if `align` isn't a power of two or the heap would outgrow `max`
+    fn heap_alloc(
+        &mut self,
+        size: u64,
+        align: u64,
+        max: u64,
+        span: Range,
+    ) -> Option<(u64, AllocId)> {
+        if !align.is_power_of_two() || self.heap_used.checked_add(size)? > max {
+            return None;
+        }
+        let base = self.heap_top.checked_next_multiple_of(align)?;
+        // zero sized blocks still get an address of their own
+        self.heap_top = base.checked_add(size.max(1))?;
+        self.heap_used += size;
+        self.heap.insert(
+            base,
+            Block {
+                bytes: vec![0; size as usize],
+                init: vec![false; size as usize],
+                span,
+            },
+        );
+        self.allocations.push(Allocation {
+            base,
+            size,
+            kind: AllocKind::Heap,
+            live: true,
+        });
+        Some((base, AllocId(self.allocations.len() - 1)))
+    }
+
+    fn heap_free(&mut self, addr: u64) -> Result<(), TrapKind> {
+        if addr == 0 {
+            return Ok(());
+        }
+        self.block(addr).map_err(|e| match e {
+            TrapKind::UseAfterFree(a) => TrapKind::DoubleFree(a),
+            e => e,
+        })?;
+        let b = self.heap.remove(&addr).unwrap();
+        let size = b.bytes.len() as u64;
+        self.heap_used -= size;
+        self.forget_stores(addr, size);
+        for a in self.allocations.iter_mut().rev() {
+            if a.kind == AllocKind::Heap && a.base == addr && a.live {
+                a.live = false;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The heap block starting at `addr`
+    fn block(&self, addr: u64) -> Result<&Block, TrapKind> {
+        self.heap.get(&addr).ok_or_else(|| {
+            let freed = self
+                .allocations
+                .iter()
+                .any(|a| a.kind == AllocKind::Heap && a.base == addr);
+            if freed {
+                TrapKind::UseAfterFree(addr)
+            } else {
+                TrapKind::InvalidFree(addr)
+            }
+        })
+    }
+
+    /// Moves the heap block at `addr` to a new block of `size` bytes, keeping its contents up to
+    /// the smaller size. `None` if the new block can't be allocated, the old one is kept then
+    fn heap_realloc(
+        &mut self,
+        addr: u64,
+        size: u64,
+        align: u64,
+        max: u64,
+        span: Range,
+    ) -> Result<Option<(u64, AllocId)>, TrapKind> {
+        if addr == 0 {
+            return Ok(self.heap_alloc(size, align, max, span));
+        }
+        let old = self.block(addr)?.bytes.len() as u64;
+        // the old block is still counted until it's freed
+        let Some((new, id)) = self.heap_alloc(size, align, max.saturating_add(old), span) else {
+            return Ok(None);
+        };
+        let keep = old.min(size) as usize;
+        let b = &self.heap[&addr];
+        let (bytes, init) = (b.bytes[..keep].to_vec(), b.init[..keep].to_vec());
+        let n = self.heap.get_mut(&new).unwrap();
+        n.bytes[..keep].copy_from_slice(&bytes);
+        n.init[..keep].copy_from_slice(&init);
+        let moved: Vec<u64> = self
+            .stores
+            .range(addr..addr + keep as u64)
+            .filter(|(a, s)| *a + s.size <= addr + keep as u64)
+            .map(|(a, _)| *a)
+            .collect();
+        for a in moved {
+            let s = self.stores.remove(&a).unwrap();
+            self.stores.insert(new + (a - addr), s);
+        }
+        self.heap_free(addr)?;
+        Ok(Some((new, id)))
+    }
+
+    /// Removes the stores overlapping `addr..addr + len`
+    fn forget_stores(&mut self, addr: u64, len: u64) {
+        let overlapping: Vec<u64> = self
+            .stores
+            .range(..addr + len)
+            .rev()
+            .take_while(|(start, s)| *start + s.size > addr)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overlapping {
+            self.stores.remove(&start);
+        }
+    }
+
+    /// The allocation `len` bytes at `addr` must be in. Pointers without provenance, e.g.
+    /// from `inttoptr`, get it from their address
+    fn allocation(
+        &self,
+        addr: u64,
+        alloc: Option<AllocId>,
+        len: u64,
+    ) -> Result<&Allocation, TrapKind> {
+        let contains = |a: &&Allocation| a.base <= addr && addr < a.base + a.size;
+        let a = match alloc {
+            Some(id) => &self.allocations[id.0],
+            None => self
+                .allocations
+                .iter()
+                .rev()
+                .filter(contains)
+                .find(|a| a.live)
+                .or_else(|| self.allocations.iter().rev().find(contains))
+                .ok_or(TrapKind::InvalidAddress(addr))?,
+        };
+        if !a.live && a.kind == AllocKind::Heap {
+            Err(TrapKind::UseAfterFree(addr))
+        } else if !a.live {
+            Err(TrapKind::DanglingPointer(addr))
+        } else if addr < a.base || addr.saturating_add(len) > a.base + a.size {
+            Err(TrapKind::OutOfBounds(addr))
+        } else {
+            Ok(a)
+        }
+    }
+
+    /// Bytes `addr..addr + len` for reading, in checked mode they must all be initialized
+    fn read_bytes(
+        &self,
+        addr: u64,
+        alloc: Option<AllocId>,
+        len: u64,
+        checked: bool,
+    ) -> Result<&[u8], TrapKind> {
+        if checked {
+            self.allocation(addr, alloc, len)?;
+        }
+        let (bytes, init) = self.bytes(addr, len)?;
+        if let Some(x) = init
+            .filter(|_| checked)
+            .and_then(|init| init.iter().position(|x| !x))
+        {
+            return Err(TrapKind::UninitializedRead(addr + x as u64));
+        }
+        Ok(bytes)
+    }
+
+    /// Records that `addr..addr + len` was stored to as a `ty`
+    fn record(&mut self, addr: u64, ty: Type<'a>, len: u64, alloc: Option<AllocId>) {
+        if let Ok((_, init)) = self.bytes_mut(addr, len) {
+            init.fill(true);
+        }
+        self.forget_stores(addr, len);
+        self.stores.insert(
+            addr,
+            Stored {
+                ty,
+                size: len,
+                alloc,
+            },
+        );
+    }
+
+    fn load(
+        &self,
+        addr: u64,
+        alloc: Option<AllocId>,
+        ty: &Type<'a>,
+        size: u64,
+        checked: bool,
+    ) -> Result<Value, TrapKind> {
+        let stored = self.stores.get(&addr).filter(|s| s.ty == *ty);
+        let bytes = self.read_bytes(addr, alloc, size, checked)?;
+        if checked
+            && self.allocation(addr, alloc, size)?.kind != AllocKind::Static
+            && stored.is_none()
+        {
+            return Err(TrapKind::TypeMismatch {
+                addr,
+                loaded: ty.to_string(),
+                stored: self.stores.get(&addr).map(|s| s.ty.to_string()),
+            });
+        }
+        let mut buf = [0; 8];
+        buf[..bytes.len().min(8)].copy_from_slice(&bytes[..bytes.len().min(8)]);
+        let value = Value::from_bits(u64::from_le_bytes(buf), ty)
+            .ok_or_else(|| TrapKind::Unsupported(format!("loading a {ty} by value")))?;
+        Ok(match (value, stored) {
+            (Value::Ptr(p, _), Some(s)) => Value::Ptr(p, s.alloc),
+            (v, _) => v,
+        })
+    }
+
+    fn store(
+        &mut self,
+        addr: u64,
+        alloc: Option<AllocId>,
+        ty: &Type<'a>,
+        size: u64,
+        value: Value,
+        checked: bool,
+    ) -> Result<(), TrapKind> {
+        if matches!(ty, Type::Ref(_)) {
+            return Err(TrapKind::Unsupported(format!("storing a {ty} by value")));
+        }
+        if checked {
+            self.allocation(addr, alloc, size)?;
+        }
+        let (dest, _) = self.bytes_mut(addr, size)?;
+        dest.copy_from_slice(&value.to_bits().to_le_bytes()[..size as usize]);
+        let alloc = match value {
+            Value::Ptr(_, a) => a,
+            _ => None,
+        };
+        self.record(addr, ty.clone(), size, alloc);
+        Ok(())
+    }
+}
+
+/// Walks the AST of a module and runs it
+pub struct Interpreter<'m, 'a, O> {
+    functions: BTreeMap<&'a str, &'m Function<'a>>,
+    hosts: BTreeMap<String, Host>,
+    /// Index of every label in its function's body
+    labels: BTreeMap<&'a str, BTreeMap<&'a str, usize>>,
+    layouts: Layouts<'m, 'a>,
+    /// Address of every header constant
+    statics: BTreeMap<&'a str, Value>,
+    memory: Memory<'a>,
+    pub output: O,
+    /// What `sys` instructions talk to, the running process if the `std` feature is enabled
+    pub system: Box<dyn System>,
+    /// Trap on out of bounds accesses, reads of uninitialized memory, dangling pointers
+    /// and loads at a different type than the last store
+    pub checked: bool,
+    pub limits: Limits,
+    fuel_used: u64,
+    depth: usize,
+    output_bytes: u64,
+}
+
+impl<'m, 'a, O: Output> Interpreter<'m, 'a, O> {
+    /// The module should have passed every check, the interpreter traps on what they catch
+    pub fn new(module: &'m Module<'a>, output: O) -> Self {
+        let mut functions = BTreeMap::new();
+        let mut labels = BTreeMap::new();
+        for item in &module.items {
+            if let Item::Function(f) = item {
+                functions.entry(f.name).or_insert(f);
+                let mut l = BTreeMap::new();
+                for (idx, node) in f.body.iter().enumerate() {
+                    if let AstNode::Label(name, _) = node {
+                        l.entry(*name).or_insert(idx);
+                    }
+                }
+                labels.entry(f.name).or_insert(l);
+            }
+        }
+        let mut memory = Memory::new();
+        let mut statics = BTreeMap::new();
+        for c in &module.header {
+            if !statics.contains_key(c.name) {
+                let mut bytes = c.value.clone().into_bytes();
+                bytes.push(0);
+                let (addr, id) = memory.add_static(&bytes);
+                statics.insert(c.name, Value::Ptr(addr, Some(id)));
+            }
+        }
+        Interpreter {
+            functions,
+            hosts: BTreeMap::new(),
+            labels,
+            layouts: compute_layouts(module, Target::default()).0,
+            statics,
+            memory,
+            output,
+            #[cfg(feature = "std")]
+            system: Box::new(crate::sys::Process::new()),
+            #[cfg(not(feature = "std"))]
+            system: Box::new(crate::sys::Isolated),
+            checked: false,
+            limits: Limits::default(),
+            fuel_used: 0,
+            depth: 0,
+            output_bytes: 0,
+        }
+    }
+
+    /// Makes `f` callable from NIR as `$name`, replacing a host function of the same name.
+    /// Functions of the module take precedence
+    pub fn register<Args, F: HostFn<Args> + 'static>(&mut self, name: &str, f: F) {
+        let name = name.strip_prefix('$').unwrap_or(name);
+        self.hosts.insert(name.into(), Host::new(f));
+    }
+
+    /// Names of the registered host functions, to resolve the module with
+    pub fn host_names(&self) -> Vec<&str> {
+        self.hosts.keys().map(|x| x.as_str()).collect()
+    }
+
+    /// Heap blocks that weren't freed, in the order they were allocated
+    pub fn leaks(&self) -> Vec<Leak> {
+        self.memory
+            .heap
+            .iter()
+            .map(|(addr, b)| Leak {
+                addr: *addr,
+                size: b.bytes.len() as u64,
+                span: b.span.clone(),
+            })
+            .collect()
+    }
+
+    /// Instructions executed so far
+    pub fn fuel_used(&self) -> u64 {
+        self.fuel_used
+    }
+
+    /// Calls `$main` without arguments
+    pub fn run_main(&mut self) -> Result<Option<Value>, Trap> {
+        self.call("main", &[], 0..0)
+    }
+
+    /// Calls the function `$name`, `span` is blamed if it doesn't exist
+    pub fn call(&mut self, name: &str, args: &[Value], span: Range) -> Result<Option<Value>, Trap> {
+        let Some(f) = self.functions.get(name).copied() else {
+            let Some(host) = self.hosts.get_mut(name) else {
+                return Err(Trap {
+                    kind: TrapKind::UndefinedFunction(name.into()),
+                    span,
+                });
+            };
+            return host.call(args).ok_or_else(|| Trap {
+                kind: TrapKind::HostMismatch {
+                    name: name.into(),
+                    expected: host.signature(),
+                    found: format!("with arguments ({})", join(args)),
+                },
+                span,
+            });
+        };
+        if self.depth >= self.limits.call_depth {
+            return Err(Trap {
+                kind: TrapKind::LimitExceeded(Limit::CallDepth),
+                span,
+            });
+        }
+        let mut locals = BTreeMap::new();
+        for (p, v) in f.params.iter().zip(args) {
+            locals.insert(p.name, *v);
+        }
+        let (sp, first) = (self.memory.sp, self.memory.allocations.len());
+        self.depth += 1;
+        let res = self.exec(f, &mut locals);
+        self.depth -= 1;
+        self.memory.release(sp, first);
+        res
+    }
+
+    fn exec(
+        &mut self,
+        f: &'m Function<'a>,
+        locals: &mut BTreeMap<&'a str, Value>,
+    ) -> Result<Option<Value>, Trap> {
+        let mut pc = 0;
+        while let Some(node) = f.body.get(pc) {
+            pc += 1;
+            let trap = |kind| Trap {
+                kind,
+                span: node.span(),
+            };
+            if !matches!(node, AstNode::Label(..)) {
+                if self.limits.fuel.is_some_and(|x| self.fuel_used >= x) {
+                    return Err(trap(TrapKind::LimitExceeded(Limit::Fuel)));
+                }
+                self.fuel_used += 1;
+            }
+            match node {
+                AstNode::Equals(target, op, _) => {
+                    let v = self.instruction(op, locals)?.ok_or_else(|| {
+                        trap(TrapKind::Unsupported("instruction without a value".into()))
+                    })?;
+                    if let AstNode::Local(name, _) = target.as_ref() {
+                        locals.insert(name, v);
+                    }
+                }
+                AstNode::Ret(_, a, _) => return Ok(Some(self.atom(a, locals)?)),
+                AstNode::RetVoid(_) => return Ok(None),
+                AstNode::Jmp(l, _) => pc = self.label(f, l, node)?,
+                AstNode::Br(_, c, t, e, _) => {
+                    let taken = self.atom(c, locals)?.to_bits() != 0;
+                    pc = self.label(f, if taken { t } else { e }, node)?;
+                }
+                AstNode::Label(..) => (),
+                _ => {
+                    self.instruction(node, locals)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn label(&self, f: &Function<'a>, l: &str, node: &AstNode) -> Result<usize, Trap> {
+        self.labels[f.name].get(l).copied().ok_or_else(|| Trap {
+            kind: TrapKind::Unsupported(format!("jump to undefined label `.{l}`")),
+            span: node.span(),
+        })
+    }
+
+    fn atom(
+        &self,
+        node: &'m AstNode<'a>,
+        locals: &BTreeMap<&'a str, Value>,
+    ) -> Result<Value, Trap> {
+        let trap = |kind| Trap {
+            kind,
+            span: node.span(),
+        };
+        match node {
+            AstNode::Local(name, _) => locals
+                .get(name)
+                .copied()
+                .ok_or_else(|| trap(TrapKind::Unsupported(format!("unassigned local `&{name}`")))),
+            AstNode::Const(ty, lit, _) => {
+                let n = parse_number(lit).unwrap_or_default();
+                Ok(match ty {
+                    Type::F(TypeSize::_32) => Value::F32(n as f32),
+                    Type::F(_) => Value::F64(n as f64),
+                    ty => Value::from_bits(n, ty)
+                        .ok_or_else(|| trap(TrapKind::Unsupported(format!("{ty} constant"))))?,
+                })
+            }
+            AstNode::StrRef(name, _) => self
+                .statics
+                .get(name)
+                .copied()
+                .ok_or_else(|| trap(TrapKind::Unsupported(format!("undefined `^{name}`")))),
+            AstNode::CPtrOffset(..) | AstNode::SPtrOffset(..) => {
+                match self.layouts.const_offset(node) {
+                    Some(Ok(x)) => Ok(Value::U(x, TypeSize::_64)),
+                    Some(Err(e)) => Err(trap(TrapKind::Unsupported(e.to_string()))),
+                    None => unreachable!(),
+                }
+            }
+            _ => Err(trap(TrapKind::Unsupported("this operand".into()))),
+        }
+    }
+
+    fn size_of(&self, ty: &Type<'a>, span: &Range) -> Result<(u64, u64), Trap> {
+        self.layouts
+            .of(ty)
+            .map(|l| (l.size, l.align))
+            .map_err(|e| Trap {
+                kind: TrapKind::Unsupported(e.to_string()),
+                span: span.clone(),
+            })
+    }
+
+    /// Runs an instruction that isn't a terminator
+    fn instruction(
+        &mut self,
+        node: &'m AstNode<'a>,
+        locals: &BTreeMap<&'a str, Value>,
+    ) -> Result<Option<Value>, Trap> {
+        let AstNode::Call(ret, target, args, span) = node else {
+            return self.eval(node, locals);
+        };
+        let AstNode::Global(name, _) = target.as_ref() else {
+            return Err(Trap {
+                kind: TrapKind::Unsupported("indirect call".into()),
+                span: span.clone(),
+            });
+        };
+        let host = self
+            .hosts
+            .get(*name)
+            .filter(|_| !self.functions.contains_key(name));
+        if let Some(host) = host {
+            if host.ret.as_ref() != ret.as_ref()
+                || !host.params.iter().eq(args.iter().map(|(t, _)| t))
+            {
+                return Err(Trap {
+                    kind: TrapKind::HostMismatch {
+                        name: (*name).into(),
+                        expected: host.signature(),
+                        found: format!(
+                            "as {}",
+                            signature(ret.as_ref(), args.iter().map(|(t, _)| t))
+                        ),
+                    },
+                    span: span.clone(),
+                });
+            }
+        }
+        let args = args
+            .iter()
+            .map(|(_, a)| self.atom(a, locals))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.call(name, &args, span.clone())
+    }
+
+    fn sys(&mut self, op: SysOp, args: &[Value], span: &Range) -> Result<Option<Value>, TrapKind> {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(Value::Ptr(0, None));
+        let fd = sext(arg(0).to_bits(), 32) as i32;
+        Ok(match op {
+            SysOp::Write => {
+                let (addr, alloc) = pointer(arg(1));
+                let len = arg(2).to_bits();
+                let bytes = self.memory.read_bytes(addr, alloc, len, self.checked)?;
+                self.output_bytes = self.output_bytes.saturating_add(len);
+                if self
+                    .limits
+                    .output_bytes
+                    .is_some_and(|x| self.output_bytes > x)
+                {
+                    return Err(TrapKind::LimitExceeded(Limit::OutputBytes));
+                }
+                Some(Value::I(self.system.write(fd, bytes), TypeSize::_64))
+            }
+            SysOp::Read => {
+                let (addr, alloc) = pointer(arg(1));
+                let len = arg(2).to_bits();
+                if self.checked {
+                    self.memory.allocation(addr, alloc, len)?;
+                }
+                let (buf, _) = self.memory.bytes_mut(addr, len)?;
+                let n = self.system.read(fd, buf);
+                for i in 0..n.max(0) as u64 {
+                    self.memory.record(addr + i, Type::U(TypeSize::_8), 1, None);
+                }
+                Some(Value::I(n, TypeSize::_64))
+            }
+            SysOp::Exit => return Err(TrapKind::Exit(fd)),
+            SysOp::Clock => Some(Value::U(self.system.clock(), TypeSize::_64)),
+            SysOp::Alloc => {
+                let (size, align) = (arg(0).to_bits(), arg(1).to_bits());
+                let max = self.limits.heap_bytes;
+                Some(heap_ptr(self.memory.heap_alloc(
+                    size,
+                    align,
+                    max,
+                    span.clone(),
+                )))
+            }
+            SysOp::Free => {
+                self.memory.heap_free(pointer(arg(0)).0)?;
+                None
+            }
+        })
+    }
+
+    /// Runs an instruction other than a call. Kept out of line so its large frame isn't part of
+    /// every level of recursion of the interpreted program
+    #[inline(never)]
+    fn eval(
+        &mut self,
+        node: &'m AstNode<'a>,
+        locals: &BTreeMap<&'a str, Value>,
+    ) -> Result<Option<Value>, Trap> {
+        let span = node.span();
+        let trap = |kind| Trap {
+            kind,
+            span: span.clone(),
+        };
+        if let Some((op, ty, a, b)) = node.binop() {
+            let (a, b) = (self.atom(a, locals)?, self.atom(b, locals)?);
+            return binop(op, ty, a, b).map(Some).map_err(trap);
+        }
+        if let Some((op, ty, a)) = node.unop() {
+            let a = self.atom(a, locals)?;
+            return Ok(Some(unop(op, ty, a)));
+        }
+        Ok(match node {
+            AstNode::Cpy(a, _) => Some(self.atom(a, locals)?),
+            AstNode::Cast(op, to, from, a, _) => {
+                let a = self.atom(a, locals)?;
+                Some(cast(*op, to, from, a).ok_or_else(|| {
+                    trap(TrapKind::Unsupported(format!(
+                        "{} from {from} to {to}",
+                        op.name()
+                    )))
+                })?)
+            }
+            AstNode::Stalloc(ty, n, _) => {
+                let (size, align) = self.size_of(ty, &span)?;
+                let n = self.atom(n, locals)?.to_bits();
+                let bytes = size
+                    .checked_mul(n)
+                    .ok_or(trap(TrapKind::LimitExceeded(Limit::StackBytes)))?;
+                let (addr, id) = self
+                    .memory
+                    .alloc(bytes, align, self.limits.stack_bytes)
+                    .map_err(trap)?;
+                Some(Value::Ptr(addr, Some(id)))
+            }
+            AstNode::Halloc(ty, n, _) => {
+                let (size, align) = self.size_of(ty, &span)?;
+                let n = self.atom(n, locals)?.to_bits();
+                let max = self.limits.heap_bytes;
+                Some(heap_ptr(size.checked_mul(n).and_then(|bytes| {
+                    self.memory.heap_alloc(bytes, align, max, span.clone())
+                })))
+            }
+            AstNode::Hrealloc(ty, p, n, _) => {
+                let (size, align) = self.size_of(ty, &span)?;
+                let (addr, _) = pointer(self.atom(p, locals)?);
+                let n = self.atom(n, locals)?.to_bits();
+                let max = self.limits.heap_bytes;
+                let Some(bytes) = size.checked_mul(n) else {
+                    return Ok(Some(Value::Ptr(0, None)));
+                };
+                let block = self
+                    .memory
+                    .heap_realloc(addr, bytes, align, max, span.clone());
+                Some(heap_ptr(block.map_err(trap)?))
+            }
+            AstNode::Hfree(p, _) => {
+                let (addr, _) = pointer(self.atom(p, locals)?);
+                self.memory.heap_free(addr).map_err(trap)?;
+                None
+            }
+            AstNode::Ptroffset(ty, p, i, _) => {
+                let (size, _) = self.size_of(ty, &span)?;
+                let (p, alloc) = pointer(self.atom(p, locals)?);
+                let i = match self.atom(i, locals)? {
+                    Value::I(v, _) => v as u64,
+                    v => v.to_bits(),
+                };
+                Some(Value::Ptr(p.wrapping_add(i.wrapping_mul(size)), alloc))
+            }
+            AstNode::Load(ty, p, _) => {
+                let (size, _) = self.size_of(ty, &span)?;
+                let (addr, alloc) = pointer(self.atom(p, locals)?);
+                let v = self.memory.load(addr, alloc, ty, size, self.checked);
+                Some(v.map_err(trap)?)
+            }
+            AstNode::Store(ty, p, v, _) => {
+                let (size, _) = self.size_of(ty, &span)?;
+                let (addr, alloc) = pointer(self.atom(p, locals)?);
+                let v = self.atom(v, locals)?;
+                let res = self.memory.store(addr, alloc, ty, size, v, self.checked);
+                res.map_err(trap)?;
+                None
+            }
+            AstNode::Sys(op, args, _) => {
+                let args = args
+                    .iter()
+                    .map(|a| self.atom(a, locals))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.sys(*op, &args, &span).map_err(trap)?
+            }
+            AstNode::Dbg(_, a, _) => {
+                let v = self.atom(a, locals)?;
+                let len = v.to_string().len() as u64 + 1;
+                self.output_bytes = self.output_bytes.saturating_add(len);
+                if self
+                    .limits
+                    .output_bytes
+                    .is_some_and(|x| self.output_bytes > x)
+                {
+                    return Err(trap(TrapKind::LimitExceeded(Limit::OutputBytes)));
+                }
+                self.output.dbg(v);
+                None
+            }
+            _ => return Err(trap(TrapKind::Unsupported("instruction".into()))),
+        })
+    }
+}
+
+/// A pointer to a new heap block, null if there is none
+fn heap_ptr(block: Option<(u64, AllocId)>) -> Value {
+    match block {
+        Some((addr, id)) => Value::Ptr(addr, Some(id)),
+        None => Value::Ptr(0, None),
+    }
+}
+
+fn join(values: &[Value]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(", ")
+}
+
+fn pointer(v: Value) -> (u64, Option<AllocId>) {
+    match v {
+        Value::Ptr(p, alloc) => (p, alloc),
+        v => (v.to_bits(), None),
+    }
+}
+
+fn binop(op: BinOp, ty: &Type, a: Value, b: Value) -> Result<Value, TrapKind> {
+    macro_rules! float {
+        ($a:expr, $b:expr, $wrap:path) => {{
+            let (a, b) = ($a, $b);
+            match op {
+                BinOp::Add => $wrap(a + b),
+                BinOp::Sub => $wrap(a - b),
+                BinOp::Mul => $wrap(a * b),
+                BinOp::Div => $wrap(a / b),
+                BinOp::Rem => $wrap(a % b),
+                BinOp::Eq => Value::Bool(a == b),
+                BinOp::Ne => Value::Bool(a != b),
+                BinOp::Lt => Value::Bool(a < b),
+                BinOp::Le => Value::Bool(a <= b),
+                BinOp::Gt => Value::Bool(a > b),
+                BinOp::Ge => Value::Bool(a >= b),
+                _ => return Err(TrapKind::Unsupported(format!("{} on floats", op.name()))),
+            }
+        }};
+    }
+    let (x, y) = (a.to_bits(), b.to_bits());
+    let (signed, bits) = match ty {
+        Type::I(s) => (true, s.bits()),
+        Type::U(s) => (false, s.bits()),
+        Type::F(TypeSize::_32) => {
+            return Ok(float!(a.as_f64() as f32, b.as_f64() as f32, Value::F32))
+        }
+        Type::F(_) => return Ok(float!(a.as_f64(), b.as_f64(), Value::F64)),
+        Type::Bool | Type::Ptr(_) => (false, 64),
+        Type::Ref(_) => return Err(TrapKind::Unsupported(format!("{} on structs", op.name()))),
+    };
+    let (sx, sy) = (sext(x, bits), sext(y, bits));
+    let res = match op {
+        BinOp::Add => x.wrapping_add(y),
+        BinOp::Sub => x.wrapping_sub(y),
+        BinOp::Mul => x.wrapping_mul(y),
+        BinOp::Div | BinOp::Rem if y == 0 => return Err(TrapKind::DivideByZero),
+        BinOp::Div if signed && sy == -1 && sx == sext(1 << (bits - 1), bits) => {
+            return Err(TrapKind::DivideOverflow)
+        }
+        BinOp::Div if signed => sx.wrapping_div(sy) as u64,
+        BinOp::Div => x / y,
+        BinOp::Rem if signed => sx.wrapping_rem(sy) as u64,
+        BinOp::Rem => x % y,
+        BinOp::And => x & y,
+        BinOp::Or => x | y,
+        BinOp::Xor => x ^ y,
+        BinOp::Shl => x << (y % u64::from(bits)),
+        BinOp::Shr if signed => (sx >> (y % u64::from(bits))) as u64,
+        BinOp::Shr => x >> (y % u64::from(bits)),
+        BinOp::Eq => return Ok(Value::Bool(x == y)),
+        BinOp::Ne => return Ok(Value::Bool(x != y)),
+        BinOp::Lt if signed => return Ok(Value::Bool(sx < sy)),
+        BinOp::Le if signed => return Ok(Value::Bool(sx <= sy)),
+        BinOp::Gt if signed => return Ok(Value::Bool(sx > sy)),
+        BinOp::Ge if signed => return Ok(Value::Bool(sx >= sy)),
+        BinOp::Lt => return Ok(Value::Bool(x < y)),
+        BinOp::Le => return Ok(Value::Bool(x <= y)),
+        BinOp::Gt => return Ok(Value::Bool(x > y)),
+        BinOp::Ge => return Ok(Value::Bool(x >= y)),
+    };
+    Ok(Value::from_bits(res, ty).unwrap())
+}
+
+fn unop(op: UnOp, ty: &Type, a: Value) -> Value {
+    match (op, a) {
+        (UnOp::Neg, Value::F32(f)) => Value::F32(-f),
+        (UnOp::Neg, Value::F64(f)) => Value::F64(-f),
+        (UnOp::Neg, a) => Value::from_bits(a.to_bits().wrapping_neg(), ty).unwrap_or(a),
+        (UnOp::Not, Value::Bool(b)) => Value::Bool(!b),
+        (UnOp::Not, a) => Value::from_bits(!a.to_bits(), ty).unwrap_or(a),
+    }
+}
+
+fn cast(op: CastOp, to: &Type, from: &Type, a: Value) -> Option<Value> {
+    let bits = a.to_bits();
+    let int = |v: u64| Value::from_bits(v, to);
+    let float = |f: f64| match to {
+        Type::F(TypeSize::_32) => Some(Value::F32(f as f32)),
+        Type::F(_) => Some(Value::F64(f)),
+        _ => None,
+    };
+    match op {
+        CastOp::Bitcast if matches!(to, Type::Ptr(_)) => Some(a),
+        CastOp::Zext | CastOp::Trunc | CastOp::Bitcast | CastOp::Inttoptr | CastOp::Ptrtoint => {
+            int(bits)
+        }
+        CastOp::Sext => int(sext(bits, from.bits()?) as u64),
+        CastOp::Itof => match a {
+            Value::I(v, _) => float(v as f64),
+            _ => float(bits as f64),
+        },
+        CastOp::Fext | CastOp::Ftrunc => float(a.as_f64()),
+        CastOp::Ftoi => {
+            let f = a.as_f64();
+            let w = to.bits()?;
+            match to {
+                Type::I(_) => {
+                    let (min, max) = (sext(1 << (w - 1), w), (mask(w) >> 1) as i64);
+                    int((f as i64).clamp(min, max) as u64)
+                }
+                _ => int((f as u64).min(mask(w))),
+            }
+        }
+    }
+}
+
+/// Runs `$main` of a module, collecting `dbg` output into a string
+pub fn run_to_string(module: &Module) -> (Result<Option<Value>, Trap>, String) {
+    let mut i = Interpreter::new(module, String::new());
+    let res = i.run_main();
+    (res, i.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+
+    fn run(src: &str) -> (Result<Option<Value>, Trap>, String) {
+        run_to_string(&parse_module(src).unwrap())
+    }
+
+    #[test]
+    fn dbg_output() {
+        let (res, out) = run("fn $fact(u64) (u64 n) {
+    &z = eq(u64) &n, !(u64)0
+    br(bool) &z, .base, .rec
+.base
+    ret(u64) !(u64)1
+.rec
+    &m = sub(u64) &n, !(u64)1
+    &r = call(u64) $fact (u64 &m)
+    &x = mul(u64) &n, &r
+    ret(u64) &x
+}
+fn $main(s64) () {
+    &f = call(u64) $fact (u64 !(u64)20)
+    dbg(u64) &f
+    &b = sext(s64) s8 !(s8)0xF0
+    dbg(s64) &b
+    &h = shr(s8) !(s8)0x80, !(s8)2
+    dbg(s8) &h
+    &k = ftoi(u8) f64 !(f64)300
+    dbg(u8) &k
+    &t = trunc(bool) u32 !(u32)2
+    dbg(bool) &t
+    ret(s64) &b
+}
+");
+        assert_eq!(res.unwrap(), Some(Value::I(-16, TypeSize::_64)));
+        assert_eq!(out, "2432902008176640000\n-16\n-32\n255\nfalse\n");
+    }
+
+    #[test]
+    fn traps() {
+        let trap = |body: &str| {
+            let src = format!("fn $main() () {{\n{body}\n}}\n");
+            let (res, _) = run(&src);
+            res.unwrap_err().kind
+        };
+        assert_eq!(
+            trap("&a = div(s32) !(s32)7, !(s32)0"),
+            TrapKind::DivideByZero
+        );
+        assert_eq!(
+            trap("&e = div(s8) !(s8)0x80, !(s8)0xFF"),
+            TrapKind::DivideOverflow
+        );
+        assert_eq!(trap("sys exit !(s32)3"), TrapKind::Exit(3));
+    }
+
+    #[test]
+    fn checked_traps() {
+        let src = "fn $main() () {
+    &p = stalloc u32 times !(u64)1
+    &v = load(u32) ptr &p
+    dbg(u32) &v
+}
+";
+        let module = parse_module(src).unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        assert!(i.run_main().is_ok());
+        let mut i = Interpreter::new(&module, String::new());
+        i.checked = true;
+        let trap = i.run_main().unwrap_err();
+        assert!(matches!(trap.kind, TrapKind::UninitializedRead(_)));
+        assert_eq!(i.output, "");
+    }
+
+    #[test]
+    fn trap_spans() {
+        let src = "fn $main() () {\n    &a = div(u8) !(u8)1, !(u8)0\n}\n";
+        let (res, _) = run(src);
+        assert_eq!(&src[res.unwrap_err().span], "div(u8) !(u8)1, !(u8)0");
+    }
+
+    /// The trap running `body` as `$main` in checked mode ends with
+    fn checked_trap(body: &str) -> TrapKind {
+        let src = format!("--NIR--\n^S=\"s\";\n--END--\n{body}");
+        let module = parse_module(&src).unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.checked = true;
+        i.run_main().unwrap_err().kind
+    }
+
+    #[test]
+    fn memory_errors() {
+        let oob = checked_trap(
+            "fn $main() () {
+    &p = stalloc u32 times !(u64)2
+    &q = ptroffset(u32) ptr &p !(u64)2
+    store(u32) ptr &q, !(u32)1
+}
+",
+        );
+        assert!(matches!(oob, TrapKind::OutOfBounds(_)));
+        let dangling = checked_trap(
+            "fn $f(u32*) () {
+    &p = stalloc u32 times !(u64)1
+    store(u32) ptr &p, !(u32)1
+    ret(u32*) &p
+}
+fn $main() () {
+    &p = call(u32*) $f ()
+    &v = load(u32) ptr &p
+}
+",
+        );
+        assert!(matches!(dangling, TrapKind::DanglingPointer(_)));
+        let constant = checked_trap(
+            "fn $main() () {
+    store(u8) ptr ^S, !(u8)1
+}
+",
+        );
+        assert!(matches!(constant, TrapKind::WriteToConstant(_)));
+    }
+
+    #[test]
+    fn type_mismatches() {
+        let kind = checked_trap(
+            "fn $main() () {
+    &p = stalloc u32 times !(u64)1
+    store(u32) ptr &p, !(u32)1
+    &v = load(s32) ptr &p
+}
+",
+        );
+        let TrapKind::TypeMismatch { loaded, stored, .. } = kind else {
+            panic!("{kind:?}");
+        };
+        assert_eq!(loaded, "s32");
+        assert_eq!(stored.as_deref(), Some("u32"));
+    }
+
+    #[test]
+    fn fuel() {
+        let module = parse_module(
+            "fn $main() () {
+.top
+    &x = add(u32) !(u32)1, !(u32)1
+    jmp .top
+}
+",
+        )
+        .unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.limits.fuel = Some(100);
+        let trap = i.run_main().unwrap_err();
+        assert_eq!(trap.kind, TrapKind::LimitExceeded(Limit::Fuel));
+        assert_eq!(i.fuel_used(), 100);
+
+        let module = parse_module("fn $main() () {\n    dbg(u8) !(u8)1\n}\n").unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.limits.fuel = Some(1);
+        assert!(i.run_main().is_ok());
+        assert_eq!(i.output, "1\n");
+    }
+
+    #[test]
+    fn output_limit() {
+        let module = parse_module(
+            "fn $main() () {
+    dbg(u32) !(u32)100
+    dbg(u32) !(u32)100
+}
+",
+        )
+        .unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.limits.output_bytes = Some(7);
+        let trap = i.run_main().unwrap_err();
+        assert_eq!(trap.kind, TrapKind::LimitExceeded(Limit::OutputBytes));
+        assert_eq!(i.output, "100\n");
+    }
+
+    #[test]
+    fn oversized_writes() {
+        let src = "--NIR--
+^S=\"hi\";
+--END--
+fn $main() () {
+    &n = sys write !(s32)1, ^S, !(u64)0xFFFFFFFFFFFFFFFF
+}
+";
+        let (res, _) = run(src);
+        assert!(matches!(res.unwrap_err().kind, TrapKind::InvalidAddress(_)));
+
+        let src = src.replace("0xFFFFFFFFFFFFFFFF", "2");
+        let module = parse_module(&src).unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.limits.output_bytes = Some(1);
+        let trap = i.run_main().unwrap_err();
+        assert_eq!(trap.kind, TrapKind::LimitExceeded(Limit::OutputBytes));
+    }
+
+    #[test]
+    fn leaks() {
+        let src = "fn $main() () {
+    &a = halloc u32 times !(u64)4
+    &b = halloc u8 times !(u64)3
+    &c = hrealloc(u8) ptr &b times !(u64)5
+    &d = halloc u64 times !(u64)1
+    hfree &d
+}
+";
+        let module = parse_module(src).unwrap();
+        let mut i = Interpreter::new(&module, String::new());
+        i.run_main().unwrap();
+        let leaks: Vec<_> = i
+            .leaks()
+            .into_iter()
+            .map(|l| (l.size, &src[l.span]))
+            .collect();
+        assert_eq!(
+            leaks,
+            [
+                (16, "halloc u32 times !(u64)4"),
+                (5, "hrealloc(u8) ptr &b times !(u64)5")
+            ]
+        );
+    }
+
+    #[test]
+    fn heap_errors() {
+        let double = checked_trap(
+            "fn $main() () {
+    &a = halloc u32 times !(u64)1
+    hfree &a
+    hfree &a
+}
+",
+        );
+        assert!(matches!(double, TrapKind::DoubleFree(_)));
+        let invalid = checked_trap(
+            "fn $main() () {
+    &a = halloc u32 times !(u64)2
+    &b = ptroffset(u32) ptr &a !(u64)1
+    hfree &b
+}
+",
+        );
+        assert!(matches!(invalid, TrapKind::InvalidFree(_)));
+        let realloc = checked_trap(
+            "fn $main() () {
+    &a = halloc u32 times !(u64)1
+    store(u32) ptr &a, !(u32)1
+    &b = hrealloc(u32) ptr &a times !(u64)1024
+    &v = load(u32) ptr &b
+    &w = load(u32) ptr &a
+}
+",
+        );
+        assert!(matches!(realloc, TrapKind::UseAfterFree(_)));
+    }
+}
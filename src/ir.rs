@@ -0,0 +1,306 @@
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+use crate::ast::{BinOp, CastOp, SysOp, Type, TypeSize, UnOp};
+use crate::Range;
+
+macro_rules! id {
+    ($(#[$m:meta])* $name:ident) => {
+        $(#[$m])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            pub fn index(self) -> usize {
+                self.0 as usize
+            }
+        }
+    };
+}
+
+id!(
+    /// An interned name, see [`Interner`]
+    Symbol
+);
+id!(
+    /// Index into `Module::structs`
+    StructId
+);
+id!(
+    /// Index into `Module::functions`
+    FuncId
+);
+id!(
+    /// Index into `Module::strings`
+    StrId
+);
+id!(
+    /// Index into `Function::locals`, parameters come first
+    LocalId
+);
+id!(
+    /// Index into `Function::blocks`, the entry block is 0
+    BlockId
+);
+id!(
+    /// Index into `Function::insts`, names the value the instruction produces
+    ValueId
+);
+
+/// Stores every name once
+#[derive(Debug, Default, Clone)]
+pub struct Interner {
+    names: Vec<String>,
+    ids: BTreeMap<String, Symbol>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(s) = self.ids.get(name) {
+            return *s;
+        }
+        let s = Symbol(self.names.len() as u32);
+        self.names.push(name.into());
+        self.ids.insert(name.into(), s);
+        s
+    }
+
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, s: Symbol) -> &str {
+        &self.names[s.index()]
+    }
+}
+
+/// A type with struct names resolved
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ty {
+    I(TypeSize),
+    U(TypeSize),
+    F(TypeSize),
+    Bool,
+    Ptr(Box<Ty>),
+    Struct(StructId),
+}
+
+impl Ty {
+    pub fn ptr(self) -> Ty {
+        Ty::Ptr(Box::new(self))
+    }
+
+    /// `t` if it doesn't refer to a struct
+    pub fn primitive(t: &Type) -> Option<Ty> {
+        Some(match t {
+            Type::I(s) => Ty::I(*s),
+            Type::U(s) => Ty::U(*s),
+            Type::F(s) => Ty::F(*s),
+            Type::Bool => Ty::Bool,
+            Type::Ptr(t) => Ty::primitive(t)?.ptr(),
+            Type::Ref(_) => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: Option<Symbol>,
+    pub ty: Ty,
+}
+
+#[derive(Debug, Clone)]
+pub struct StructDef {
+    pub name: Symbol,
+    pub fields: Vec<Field>,
+    pub packed: bool,
+}
+
+/// A header constant
+#[derive(Debug, Clone)]
+pub struct StrConst {
+    pub name: Symbol,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Local {
+    pub name: Symbol,
+    pub ty: Ty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Callee {
+    Function(FuncId),
+    /// A function the embedder provides, see `Interpreter::register`
+    Host(Symbol),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstKind {
+    /// Bits of a constant of the instruction's type, floats are stored as their IEEE bits
+    Const(u64),
+    /// Address of a header constant
+    Str(StrId),
+    /// Byte offset of the `n`th element of an array, from `cptroffset`
+    ElemOffset(Ty, u64),
+    /// Byte offset of a field reached by taking these field indices, from `sptroffset`
+    FieldOffset(Ty, Vec<u32>),
+    ReadLocal(LocalId),
+    WriteLocal(LocalId, ValueId),
+    /// Operands have the type, the result is `bool` for comparisons
+    Binary(BinOp, Ty, ValueId, ValueId),
+    Unary(UnOp, Ty, ValueId),
+    /// From the type to the instruction's type
+    Cast(CastOp, Ty, ValueId),
+    Call(Callee, Vec<ValueId>),
+    Stalloc(Ty, ValueId),
+    Halloc(Ty, ValueId),
+    Hrealloc(Ty, ValueId, ValueId),
+    Hfree(ValueId),
+    Ptroffset(Ty, ValueId, ValueId),
+    Load(Ty, ValueId),
+    Store(Ty, ValueId, ValueId),
+    Dbg(Ty, ValueId),
+    Sys(SysOp, Vec<ValueId>),
+}
+
+impl InstKind {
+    /// Values this instruction uses, in evaluation order
+    pub fn operands(&self) -> Vec<ValueId> {
+        match self {
+            InstKind::Const(_)
+            | InstKind::Str(_)
+            | InstKind::ElemOffset(..)
+            | InstKind::FieldOffset(..)
+            | InstKind::ReadLocal(_) => Vec::new(),
+            InstKind::WriteLocal(_, a)
+            | InstKind::Unary(_, _, a)
+            | InstKind::Cast(_, _, a)
+            | InstKind::Stalloc(_, a)
+            | InstKind::Halloc(_, a)
+            | InstKind::Hfree(a)
+            | InstKind::Load(_, a)
+            | InstKind::Dbg(_, a) => alloc::vec![*a],
+            InstKind::Binary(_, _, a, b)
+            | InstKind::Hrealloc(_, a, b)
+            | InstKind::Ptroffset(_, a, b)
+            | InstKind::Store(_, a, b) => alloc::vec![*a, *b],
+            InstKind::Call(_, args) | InstKind::Sys(_, args) => args.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Inst {
+    pub kind: InstKind,
+    /// Type of the value produced, `None` if there is none
+    pub ty: Option<Ty>,
+}
+
+/// How a block ends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Terminator {
+    Ret(Option<ValueId>),
+    Jmp(BlockId),
+    /// Jumps to the first block if the value is non-zero, otherwise to the second
+    Br(ValueId, BlockId, BlockId),
+    /// After an instruction that doesn't return, like `sys exit`
+    Unreachable,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jmp(b) => alloc::vec![*b],
+            Terminator::Br(_, t, f) => alloc::vec![*t, *f],
+            Terminator::Ret(_) | Terminator::Unreachable => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub label: Option<Symbol>,
+    /// Instructions in execution order
+    pub insts: Vec<ValueId>,
+    pub term: Terminator,
+    /// Source of the terminator, empty where it was implied by falling through
+    pub term_span: Range,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: Symbol,
+    pub ret: Option<Ty>,
+    /// The first `params` locals
+    pub params: usize,
+    pub locals: Vec<Local>,
+    pub insts: Vec<Inst>,
+    /// Source of every instruction, by `ValueId`
+    pub spans: Vec<Range>,
+    pub blocks: Vec<Block>,
+    pub span: Range,
+}
+
+impl Function {
+    pub fn inst(&self, v: ValueId) -> &Inst {
+        &self.insts[v.index()]
+    }
+
+    pub fn span(&self, v: ValueId) -> &Range {
+        &self.spans[v.index()]
+    }
+
+    pub fn block(&self, b: BlockId) -> &Block {
+        &self.blocks[b.index()]
+    }
+
+    /// Adds an instruction to the arena without putting it in a block
+    pub fn push(&mut self, kind: InstKind, ty: Option<Ty>, span: Range) -> ValueId {
+        self.insts.push(Inst { kind, ty });
+        self.spans.push(span);
+        ValueId(self.insts.len() as u32 - 1)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Module {
+    pub symbols: Interner,
+    pub strings: Vec<StrConst>,
+    pub structs: Vec<StructDef>,
+    pub functions: Vec<Function>,
+}
+
+impl Module {
+    pub fn function(&self, name: &str) -> Option<FuncId> {
+        let s = self.symbols.get(name)?;
+        self.functions
+            .iter()
+            .position(|f| f.name == s)
+            .map(|x| FuncId(x as u32))
+    }
+
+    pub fn strukt(&self, name: &str) -> Option<StructId> {
+        let s = self.symbols.get(name)?;
+        self.structs
+            .iter()
+            .position(|f| f.name == s)
+            .map(|x| StructId(x as u32))
+    }
+
+    pub fn name(&self, s: Symbol) -> &str {
+        self.symbols.name(s)
+    }
+
+    /// `t` as written in NIR
+    pub fn ty(&self, t: &Ty) -> Type<'_> {
+        match t {
+            Ty::I(s) => Type::I(*s),
+            Ty::U(s) => Type::U(*s),
+            Ty::F(s) => Type::F(*s),
+            Ty::Bool => Type::Bool,
+            Ty::Ptr(t) => self.ty(t).ptr(),
+            Ty::Struct(id) => Type::Ref(self.name(self.structs[id.index()].name)),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use alloc::{collections::BTreeMap, format, vec::Vec};
+
+use crate::ast::{AstNode, Module, StructDef, StructErr, StructTable, Type};
+use crate::diagnostic::Diagnostic;
+use crate::grammar::parse_number;
+
+/// Properties of the machine code is laid out for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub pointer_bytes: u64,
+}
+
+impl Target {
+    /// The machine naklang itself runs on
+    pub const HOST: Target = Target {
+        pointer_bytes: core::mem::size_of::<usize>() as u64,
+    };
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target { pointer_bytes: 8 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    pub layout: Layout,
+    /// Byte offset of each field, in definition order
+    pub offsets: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub enum LayoutErr<'a> {
+    Struct(StructErr<'a>),
+    /// The struct contains itself other than through a pointer, so it has no finite size
+    Recursive(&'a str),
+}
+
+impl<'a> From<StructErr<'a>> for LayoutErr<'a> {
+    fn from(e: StructErr<'a>) -> Self {
+        LayoutErr::Struct(e)
+    }
+}
+
+impl core::fmt::Display for LayoutErr<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LayoutErr::Struct(e) => write!(f, "{e}"),
+            LayoutErr::Recursive(name) => write!(f, "struct `${name}` contains itself"),
+        }
+    }
+}
+
+/// Sizes, alignments and field offsets of every type in a module
+#[derive(Debug)]
+pub struct Layouts<'m, 'a> {
+    pub target: Target,
+    pub structs: StructTable<'m, 'a>,
+    computed: BTreeMap<&'a str, StructLayout>,
+}
+
+/// Lays out every struct of `module`, reporting the ones that contain themselves
+pub fn compute_layouts<'m, 'a>(
+    module: &'m Module<'a>,
+    target: Target,
+) -> (Layouts<'m, 'a>, Vec<Diagnostic>) {
+    let mut layouts = Layouts {
+        target,
+        structs: StructTable::new(module),
+        computed: BTreeMap::new(),
+    };
+    let mut diags = Vec::new();
+    let defs: Vec<_> = layouts.structs.iter().collect();
+    for s in defs {
+        let mut visiting = Vec::new();
+        if let Err(LayoutErr::Recursive(name)) = layouts.compute(s, &mut visiting) {
+            if name == s.name {
+                diags.push(
+                    Diagnostic::error(format!("struct `${name}` contains itself"), s.span.clone())
+                        .with_code("layout")
+                        .with_help(format!("contain a pointer to `${name}` instead")),
+                );
+            }
+        }
+    }
+    (layouts, diags)
+}
+
+fn align_to(offset: u64, align: u64) -> u64 {
+    offset.div_ceil(align) * align
+}
+
+impl<'m, 'a> Layouts<'m, 'a> {
+    fn compute(
+        &mut self,
+        s: &'m StructDef<'a>,
+        visiting: &mut Vec<&'a str>,
+    ) -> Result<Layout, LayoutErr<'a>> {
+        if let Some(l) = self.computed.get(s.name) {
+            return Ok(l.layout);
+        }
+        if visiting.contains(&s.name) {
+            return Err(LayoutErr::Recursive(s.name));
+        }
+        visiting.push(s.name);
+        let mut offsets = Vec::with_capacity(s.fields.len());
+        let mut size = 0;
+        let mut align = 1;
+        for field in &s.fields {
+            let l = match &field.ty {
+                Type::Ref(name) => {
+                    let def = self
+                        .structs
+                        .get(name)
+                        .ok_or(StructErr::UnknownStruct(name))?;
+                    self.compute(def, visiting)?
+                }
+                ty => self.of(ty)?,
+            };
+            if !s.packed {
+                size = align_to(size, l.align);
+                align = align.max(l.align);
+            }
+            offsets.push(size);
+            size += l.size;
+        }
+        visiting.pop();
+        let layout = Layout {
+            size: align_to(size, align),
+            align,
+        };
+        self.computed
+            .insert(s.name, StructLayout { layout, offsets });
+        Ok(layout)
+    }
+
+    pub fn of(&self, ty: &Type<'a>) -> Result<Layout, LayoutErr<'a>> {
+        let bytes = match ty {
+            Type::I(s) | Type::U(s) | Type::F(s) => u64::from(s.bits() / 8),
+            Type::Bool => 1,
+            Type::Ptr(_) => self.target.pointer_bytes,
+            Type::Ref(name) => return Ok(self.struct_layout(name)?.layout),
+        };
+        Ok(Layout {
+            size: bytes,
+            align: bytes,
+        })
+    }
+
+    pub fn struct_layout(&self, name: &'a str) -> Result<&StructLayout, LayoutErr<'a>> {
+        match self.computed.get(name) {
+            Some(l) => Ok(l),
+            None if self.structs.get(name).is_some() => Err(LayoutErr::Recursive(name)),
+            None => Err(StructErr::UnknownStruct(name).into()),
+        }
+    }
+
+    /// Byte offset and type of the field a `sptroffset` path leads to
+    pub fn field_offset(
+        &self,
+        ty: &'m Type<'a>,
+        path: &[&'a str],
+    ) -> Result<(u64, &'m Type<'a>), LayoutErr<'a>> {
+        let fp = self.structs.resolve_path(ty, path)?;
+        let mut offset = 0;
+        let mut cur = ty;
+        for idx in fp.indices {
+            // resolve_path succeeded so every step is a struct
+            let def = self.structs.resolve(cur)?;
+            offset += self.struct_layout(def.name)?.offsets[idx];
+            cur = &def.fields[idx].ty;
+        }
+        Ok((offset, fp.ty))
+    }
+
+    /// Byte offset a `cptroffset` or `sptroffset` atom stands for
+    pub fn const_offset(&self, node: &'m AstNode<'a>) -> Option<Result<u64, LayoutErr<'a>>> {
+        match node {
+            AstNode::CPtrOffset(ty, n, _) => Some(
+                self.of(ty)
+                    .map(|l| l.size * parse_number(n).unwrap_or_default()),
+            ),
+            AstNode::SPtrOffset(ty, path, _) => Some(self.field_offset(ty, path).map(|x| x.0)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+    use alloc::string::ToString;
+
+    const SRC: &str = "struct $Pad { a=u8, b=u32, c=u16 }
+struct $Outer { x=u8, inner=$Pad, p=$Outer*, y=bool }
+packed struct $Packed { a=u8, b=u64, c=$Pad }
+struct $Empty { }
+";
+
+    #[test]
+    fn padded_structs() {
+        let module = parse_module(SRC).unwrap();
+        let (layouts, diags) = compute_layouts(&module, Target::default());
+        assert!(diags.is_empty());
+        let pad = layouts.struct_layout("Pad").unwrap();
+        assert_eq!(pad.offsets, [0, 4, 8]);
+        assert_eq!(pad.layout, Layout { size: 12, align: 4 });
+        let packed = layouts.struct_layout("Packed").unwrap();
+        assert_eq!(packed.offsets, [0, 1, 9]);
+        assert_eq!(packed.layout, Layout { size: 21, align: 1 });
+        let empty = layouts.struct_layout("Empty").unwrap();
+        assert_eq!(empty.layout, Layout { size: 0, align: 1 });
+    }
+
+    #[test]
+    fn nested_structs() {
+        let outer = Type::Ref("Outer");
+        let module = parse_module(SRC).unwrap();
+        let (layouts, _) = compute_layouts(&module, Target::default());
+        let l = layouts.struct_layout("Outer").unwrap();
+        assert_eq!(l.offsets, [0, 4, 16, 24]);
+        assert_eq!(l.layout, Layout { size: 32, align: 8 });
+        let (offset, ty) = layouts.field_offset(&outer, &["inner", "c"]).unwrap();
+        assert_eq!((offset, ty), (12, &Type::U(crate::ast::TypeSize::_16)));
+
+        let (small, _) = compute_layouts(&module, Target { pointer_bytes: 4 });
+        let l = small.struct_layout("Outer").unwrap();
+        assert_eq!(l.offsets, [0, 4, 16, 20]);
+        assert_eq!(l.layout, Layout { size: 24, align: 4 });
+    }
+
+    #[test]
+    fn recursive_structs() {
+        let module = parse_module("struct $A { b=$B }\nstruct $B { a=$A }\n").unwrap();
+        let (layouts, diags) = compute_layouts(&module, Target::default());
+        let messages: Vec<_> = diags.into_iter().map(|d| d.message).collect();
+        assert_eq!(
+            messages,
+            ["struct `$A` contains itself", "struct `$B` contains itself"]
+        );
+        assert_eq!(
+            layouts.struct_layout("A").unwrap_err().to_string(),
+            "struct `$A` contains itself"
+        );
+    }
+}
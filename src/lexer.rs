@@ -1,28 +1,68 @@
-use logos::Logos;
+use logos::{FilterResult, Logos};
 
-#[derive(Logos, Debug, PartialEq, Clone, Copy)]
+/// Lexer state shared between callbacks
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LexState {
+    /// Set between `--NIR--` and `--END--`. Inside the header a `;` terminates a constant
+    /// instead of starting a comment
+    pub in_header: bool,
+}
+
+/// Inside the header a `;` is a token of its own, elsewhere it starts a comment up to the end
+/// of the line
+fn semicolon(lex: &mut logos::Lexer<Tok>) -> FilterResult<(), ()> {
+    if lex.extras.in_header {
+        FilterResult::Emit(())
+    } else {
+        let rest = lex.remainder();
+        lex.bump(rest.find('\n').unwrap_or(rest.len()));
+        FilterResult::Skip
+    }
+}
+
+#[derive(Logos, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[logos(extras = LexState)]
 pub enum Tok {
-    #[regex(r"[ \t\n\f]*;.*\n?", logos::skip)]
-    #[regex(r"[ \t\n\f]+", logos::skip)]
+    #[regex(r"//[^\n]*", logos::skip)]
+    #[regex(r"[ \t\r\n\f]+", logos::skip)]
     Err,
     #[token("$")]
     Dollar,
     #[token("&")]
     Ampersand,
+    #[token("^")]
+    Caret,
     #[token(",")]
     Comma,
     #[token(".")]
     Dot,
     #[token("!")]
     Bang,
+    #[token("*")]
+    Star,
+    // Outside of the header this is a comment and gets skipped
+    #[token(";", semicolon)]
+    Semicolon,
+    #[token("--NIR--", |lex| lex.extras.in_header = true)]
+    HeaderStart,
+    #[token("--END--", |lex| lex.extras.in_header = false)]
+    HeaderEnd,
     #[regex("[_a-zA-Z][_a-zA-Z0-9]*")]
     Ident,
+    #[regex(r#""([^"\\\n]|\\[^\n])*""#)]
+    Str,
     #[regex("\\(")]
     OpenBracket,
     #[regex("\\)")]
     CloseBracket,
+    #[token("{")]
+    OpenBrace,
+    #[token("}")]
+    CloseBrace,
     #[regex("(0x[a-fA-F0-9]+)|(0b[10]+)|([0-9]+)+")]
     Number,
     #[regex("=")]
     Equals,
+    /// Ends every token stream after the last token, it never matches any text
+    Eof,
 }
@@ -1,7 +1,24 @@
 #![no_std]
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+pub mod ast;
+pub mod builder;
+pub mod diagnostic;
+pub mod grammar;
+pub mod host;
+pub mod interp;
+pub mod ir;
+pub mod layout;
 mod lexer;
+pub mod lower;
 mod parser;
+pub mod print;
+pub mod resolve;
+pub mod sys;
+pub mod typeck;
+pub mod verify;
 
-pub use lexer::Tok;
+pub use grammar::{parse_module, parse_module_partial};
+pub use lexer::{LexState, Tok};
 pub use parser::*;
@@ -0,0 +1,422 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::ast::{self, AstNode, Item, StructTable, SysOp, Type, TypeSize};
+use crate::diagnostic::Diagnostic;
+use crate::grammar::parse_number;
+use crate::ir::*;
+use crate::layout::{compute_layouts, Target};
+use crate::resolve::resolve_module_with;
+use crate::typeck::{check_module, FunctionTypes};
+use crate::verify::{verify_module, Cfg};
+use crate::Range;
+
+/// Checks `module` and lowers it to the arena IR, `None` if the checks report an error
+pub fn lower_module(module: &ast::Module) -> (Option<Module>, Vec<Diagnostic>) {
+    lower_module_with(module, &[])
+}
+
+/// Like [`lower_module`], `hosts` are the names of functions provided by the embedder
+pub fn lower_module_with(
+    module: &ast::Module,
+    hosts: &[&str],
+) -> (Option<Module>, Vec<Diagnostic>) {
+    let mut diags = verify_module(module);
+    diags.extend(resolve_module_with(module, hosts).1);
+    let (types, tyerrs) = check_module(module);
+    diags.extend(tyerrs);
+    diags.extend(compute_layouts(module, Target::default()).1);
+    if diags.iter().any(|d| d.is_error()) {
+        return (None, diags);
+    }
+
+    let mut l = Lowerer {
+        structs: StructTable::new(module),
+        out: Module::default(),
+        struct_ids: BTreeMap::new(),
+        func_ids: BTreeMap::new(),
+        str_ids: BTreeMap::new(),
+    };
+    for c in &module.header {
+        let name = l.out.symbols.intern(c.name);
+        l.str_ids.insert(c.name, StrId(l.out.strings.len() as u32));
+        l.out.strings.push(StrConst {
+            name,
+            value: c.value.clone(),
+        });
+    }
+    for item in &module.items {
+        match item {
+            Item::Struct(s) => {
+                l.struct_ids
+                    .insert(s.name, StructId(l.struct_ids.len() as u32));
+            }
+            Item::Function(f) => {
+                l.func_ids.insert(f.name, FuncId(l.func_ids.len() as u32));
+            }
+        }
+    }
+    for item in &module.items {
+        match item {
+            Item::Struct(s) => {
+                let def = StructDef {
+                    name: l.out.symbols.intern(s.name),
+                    fields: s
+                        .fields
+                        .iter()
+                        .map(|f| Field {
+                            name: f.name.map(|n| l.out.symbols.intern(n)),
+                            ty: l.ty(&f.ty),
+                        })
+                        .collect(),
+                    packed: s.packed,
+                };
+                l.out.structs.push(def);
+            }
+            Item::Function(f) => {
+                let f = l.function(f, &types.functions[f.name]);
+                l.out.functions.push(f);
+            }
+        }
+    }
+    (Some(l.out), diags)
+}
+
+/// Bits of a constant, see [`InstKind::Const`]
+fn const_bits(ty: &Type, lit: &str) -> u64 {
+    let n = parse_number(lit).unwrap_or_default();
+    match ty {
+        Type::F(TypeSize::_32) => u64::from((n as f32).to_bits()),
+        Type::F(_) => (n as f64).to_bits(),
+        _ => n,
+    }
+}
+
+struct Lowerer<'m, 'a> {
+    structs: StructTable<'m, 'a>,
+    out: Module,
+    struct_ids: BTreeMap<&'a str, StructId>,
+    func_ids: BTreeMap<&'a str, FuncId>,
+    str_ids: BTreeMap<&'a str, StrId>,
+}
+
+/// State while lowering one function
+struct FnState<'a> {
+    f: Function,
+    locals: BTreeMap<&'a str, LocalId>,
+    block: BlockId,
+}
+
+impl FnState<'_> {
+    fn emit(&mut self, kind: InstKind, ty: Option<Ty>, span: Range) -> ValueId {
+        let v = self.f.push(kind, ty, span);
+        self.f.blocks[self.block.index()].insts.push(v);
+        v
+    }
+}
+
+impl<'m, 'a> Lowerer<'m, 'a> {
+    fn ty(&self, t: &Type) -> Ty {
+        match t {
+            Type::I(s) => Ty::I(*s),
+            Type::U(s) => Ty::U(*s),
+            Type::F(s) => Ty::F(*s),
+            Type::Bool => Ty::Bool,
+            Type::Ptr(t) => self.ty(t).ptr(),
+            Type::Ref(name) => Ty::Struct(self.struct_ids[name]),
+        }
+    }
+
+    fn function(&mut self, f: &'m ast::Function<'a>, types: &FunctionTypes<'a>) -> Function {
+        let mut locals = BTreeMap::new();
+        let mut defs = Vec::new();
+        let names = f
+            .params
+            .iter()
+            .map(|p| p.name)
+            .chain(types.locals.keys().copied());
+        for name in names {
+            if !locals.contains_key(name) {
+                locals.insert(name, LocalId(defs.len() as u32));
+                defs.push(Local {
+                    name: self.out.symbols.intern(name),
+                    ty: self.ty(&types.locals[name]),
+                });
+            }
+        }
+
+        let cfg = Cfg::new(&f.body);
+        let blocks = cfg
+            .blocks
+            .iter()
+            .map(|b| Block {
+                label: b.label.map(|l| self.out.symbols.intern(l)),
+                insts: Vec::new(),
+                term: Terminator::Unreachable,
+                term_span: 0..0,
+            })
+            .collect();
+        let mut s = FnState {
+            f: Function {
+                name: self.out.symbols.intern(f.name),
+                ret: f.ret.as_ref().map(|t| self.ty(t)),
+                params: f.params.len(),
+                locals: defs,
+                insts: Vec::new(),
+                spans: Vec::new(),
+                blocks,
+                span: f.span.clone(),
+            },
+            locals,
+            block: BlockId(0),
+        };
+        let label = |l: &str| BlockId(cfg.labels[l] as u32);
+        for (idx, b) in cfg.blocks.iter().enumerate() {
+            s.block = BlockId(idx as u32);
+            let mut term = None;
+            for node in &f.body[b.start..b.end] {
+                let t = match node {
+                    AstNode::Label(..) => continue,
+                    AstNode::Ret(_, a, _) => Terminator::Ret(Some(self.atom(&mut s, a))),
+                    AstNode::RetVoid(_) => Terminator::Ret(None),
+                    AstNode::Jmp(l, _) => Terminator::Jmp(label(l)),
+                    AstNode::Br(_, c, t, e, _) => {
+                        Terminator::Br(self.atom(&mut s, c), label(t), label(e))
+                    }
+                    AstNode::Sys(SysOp::Exit, ..) => {
+                        self.op(&mut s, node);
+                        Terminator::Unreachable
+                    }
+                    _ => {
+                        self.stmt(&mut s, node);
+                        continue;
+                    }
+                };
+                term = Some((t, node.span()));
+            }
+            let (term, span) = term.unwrap_or_else(|| {
+                let t = if idx + 1 < cfg.blocks.len() {
+                    Terminator::Jmp(BlockId(idx as u32 + 1))
+                } else {
+                    Terminator::Ret(None)
+                };
+                (t, 0..0)
+            });
+            let block = &mut s.f.blocks[idx];
+            block.term = term;
+            block.term_span = span;
+        }
+        s.f
+    }
+
+    fn stmt(&mut self, s: &mut FnState<'a>, node: &'m AstNode<'a>) {
+        match node {
+            AstNode::Equals(target, op, r) => {
+                let v = self.op(s, op);
+                if let AstNode::Local(name, _) = target.as_ref() {
+                    let local = s.locals[name];
+                    s.emit(InstKind::WriteLocal(local, v), None, r.clone());
+                }
+            }
+            _ => {
+                self.op(s, node);
+            }
+        }
+    }
+
+    fn atom(&mut self, s: &mut FnState<'a>, node: &'m AstNode<'a>) -> ValueId {
+        let u64_ty = Some(Ty::U(TypeSize::_64));
+        let (kind, ty) = match node {
+            AstNode::Local(name, _) => {
+                let local = s.locals[name];
+                let ty = s.f.locals[local.index()].ty.clone();
+                (InstKind::ReadLocal(local), Some(ty))
+            }
+            AstNode::Const(t, lit, _) => (InstKind::Const(const_bits(t, lit)), Some(self.ty(t))),
+            AstNode::StrRef(name, _) => (
+                InstKind::Str(self.str_ids[name]),
+                Some(Ty::U(TypeSize::_8).ptr()),
+            ),
+            AstNode::CPtrOffset(t, n, _) => (
+                InstKind::ElemOffset(self.ty(t), parse_number(n).unwrap_or_default()),
+                u64_ty,
+            ),
+            AstNode::SPtrOffset(t, path, _) => {
+                let indices = match self.structs.resolve_path(t, path) {
+                    Ok(p) => p.indices.into_iter().map(|x| x as u32).collect(),
+                    Err(_) => unreachable!("checked by the resolver"),
+                };
+                (InstKind::FieldOffset(self.ty(t), indices), u64_ty)
+            }
+            _ => unreachable!("checked by the type checker"),
+        };
+        s.emit(kind, ty, node.span())
+    }
+
+    /// Lowers an instruction, for those without a result the value is a placeholder
+    fn op(&mut self, s: &mut FnState<'a>, node: &'m AstNode<'a>) -> ValueId {
+        let span = node.span();
+        if let Some((op, t, a, b)) = node.binop() {
+            let (a, b) = (self.atom(s, a), self.atom(s, b));
+            let ty = if op.is_comparison() {
+                Ty::Bool
+            } else {
+                self.ty(t)
+            };
+            return s.emit(InstKind::Binary(op, self.ty(t), a, b), Some(ty), span);
+        }
+        if let Some((op, t, a)) = node.unop() {
+            let a = self.atom(s, a);
+            return s.emit(InstKind::Unary(op, self.ty(t), a), Some(self.ty(t)), span);
+        }
+        let (kind, ty) = match node {
+            AstNode::Cpy(a, _) => return self.atom(s, a),
+            AstNode::Cast(op, to, from, a, _) => {
+                let a = self.atom(s, a);
+                (InstKind::Cast(*op, self.ty(from), a), Some(self.ty(to)))
+            }
+            AstNode::Call(ret, target, args, _) => {
+                let AstNode::Global(name, _) = target.as_ref() else {
+                    unreachable!("checked by the type checker")
+                };
+                let callee = match self.func_ids.get(name) {
+                    Some(f) => Callee::Function(*f),
+                    None => Callee::Host(self.out.symbols.intern(name)),
+                };
+                let args = args.iter().map(|(_, a)| self.atom(s, a)).collect();
+                (
+                    InstKind::Call(callee, args),
+                    ret.as_ref().map(|t| self.ty(t)),
+                )
+            }
+            AstNode::Stalloc(t, n, _) => {
+                let n = self.atom(s, n);
+                (InstKind::Stalloc(self.ty(t), n), Some(self.ty(t).ptr()))
+            }
+            AstNode::Halloc(t, n, _) => {
+                let n = self.atom(s, n);
+                (InstKind::Halloc(self.ty(t), n), Some(self.ty(t).ptr()))
+            }
+            AstNode::Hrealloc(t, p, n, _) => {
+                let (p, n) = (self.atom(s, p), self.atom(s, n));
+                (InstKind::Hrealloc(self.ty(t), p, n), Some(self.ty(t).ptr()))
+            }
+            AstNode::Hfree(p, _) => (InstKind::Hfree(self.atom(s, p)), None),
+            AstNode::Ptroffset(t, p, i, _) => {
+                let (p, i) = (self.atom(s, p), self.atom(s, i));
+                (
+                    InstKind::Ptroffset(self.ty(t), p, i),
+                    Some(self.ty(t).ptr()),
+                )
+            }
+            AstNode::Load(t, p, _) => {
+                let p = self.atom(s, p);
+                (InstKind::Load(self.ty(t), p), Some(self.ty(t)))
+            }
+            AstNode::Store(t, p, v, _) => {
+                let (p, v) = (self.atom(s, p), self.atom(s, v));
+                (InstKind::Store(self.ty(t), p, v), None)
+            }
+            AstNode::Dbg(t, a, _) => (InstKind::Dbg(self.ty(t), self.atom(s, a)), None),
+            AstNode::Sys(op, args, _) => {
+                let args = args.iter().map(|a| self.atom(s, a)).collect();
+                (InstKind::Sys(*op, args), op.ret().map(|t| self.ty(&t)))
+            }
+            _ => unreachable!("not an instruction"),
+        };
+        s.emit(kind, ty, span)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+    use crate::print::print_ir;
+
+    const SRC: &str = "struct $P { x=u8, y=u32 }
+fn $sum(u32) (u32 n) {
+    &acc = cpy !(u32)0
+.loop
+    &done = eq(u32) &n, !(u32)0
+    br(bool) &done, .end, .body
+.body
+    &acc = add(u32) &acc, &n
+    &n = sub(u32) &n, !(u32)1
+    jmp .loop
+.end
+    ret(u32) &acc
+}
+fn $main() () {
+    &p = stalloc $P times !(u64)1
+    &o = cpy sptroffset $P, y
+    &s = call(u32) $sum (u32 !(u32)3)
+    dbg(u32) &s
+}
+";
+
+    #[test]
+    fn lowers_blocks_and_structs() {
+        let (ir, diags) = lower_module(&parse_module(SRC).unwrap());
+        assert!(diags.iter().all(|d| !d.is_error()));
+        let ir = ir.unwrap();
+        assert_eq!(ir.structs[0].fields.len(), 2);
+
+        let sum = &ir.functions[ir.function("sum").unwrap().index()];
+        let names: Vec<&str> = sum.locals.iter().map(|l| ir.name(l.name)).collect();
+        // the assigned parameter stays one local
+        assert_eq!(names, ["n", "acc", "done"]);
+        let terms: Vec<&Terminator> = sum.blocks.iter().map(|b| &b.term).collect();
+        assert!(matches!(
+            terms[..],
+            [
+                Terminator::Jmp(BlockId(1)),
+                Terminator::Br(_, BlockId(3), BlockId(2)),
+                Terminator::Jmp(BlockId(1)),
+                Terminator::Ret(Some(_)),
+            ]
+        ));
+        let main = &ir.functions[ir.function("main").unwrap().index()];
+        assert_eq!(main.blocks[0].term, Terminator::Ret(None));
+    }
+
+    #[test]
+    fn prints_lowered_ir() {
+        let (ir, _) = lower_module(&parse_module(SRC).unwrap());
+        assert_eq!(
+            print_ir(&ir.unwrap()),
+            "struct $P {x=u8, y=u32}
+
+fn $sum(u32) (u32 n) {
+    &acc = cpy !(u32)0
+    jmp .loop
+.loop
+    &done = eq(u32) &n, !(u32)0
+    br(bool) &done, .end, .body
+.body
+    &acc = add(u32) &acc, &n
+    &n = sub(u32) &n, !(u32)1
+    jmp .loop
+.end
+    ret(u32) &acc
+}
+
+fn $main() () {
+    &p = stalloc $P times !(u64)1
+    &o = cpy sptroffset $P, y
+    &s = call(u32) $sum (u32 !(u32)3)
+    dbg(u32) &s
+    ret
+}
+"
+        );
+    }
+
+    #[test]
+    fn partial_modules_are_rejected() {
+        let src = "fn $main() () {\n    &x = bogus\n    ret\n}\n";
+        let (module, _) = crate::parse_module_partial(src);
+        let (ir, diags) = lower_module(&module.unwrap());
+        assert!(ir.is_none());
+        assert!(diags.iter().any(|d| d.is_error() && d.span == (20..30)));
+    }
+}
@@ -1,282 +1,396 @@
-use logos::Logos;
-use naklang::{reserved, tok, BoxedParser, Parser, ParserErr, Range, Tok, Token, TokenStream};
+use std::fmt::Display;
+use std::io::{IsTerminal, Read};
+use std::process::ExitCode;
 
-#[derive(Debug)]
-pub enum TypeSize {
-    _8,
-    _16,
-    _32,
-    _64,
+use naklang::ast::{Function, Item, Module};
+use naklang::diagnostic::{render, to_json, Diagnostic};
+use naklang::interp::{Interpreter, Output, Trap, TrapKind, Value};
+use naklang::layout::{compute_layouts, Target};
+use naklang::lower::lower_module;
+use naklang::parse_module;
+use naklang::print::{print_ir, print_module, print_module_with_comments};
+use naklang::resolve::resolve_module;
+use naklang::typeck::check_module;
+use naklang::verify::verify_module;
+use naklang::Range;
+
+const USAGE: &str = "usage: naklang <command> [options] [FILE]
+
+commands:
+    check [FILE]                    parse and check a module
+    run [--checked] [--fuel N] [FILE]
+                                    check a module and interpret it, starting at $main
+    fmt [FILE...]                   format files in place, without files stdin to stdout
+    emit --target <TARGET> [FILE]   check a module and print it for a backend
+
+targets:
+    nir    canonical NIR
+    ir     the lowered IR, written as NIR
+
+options:
+    --color <WHEN>             colour diagnostics: auto, always or never
+    --message-format <FMT>     human, or json for one JSON object per line
+
+FILE defaults to stdin, `-` reads stdin too.
+Exits with 1 if the module has errors or traps, 2 for usage errors and
+unreadable files. `run` exits with the code given to `sys exit`, or the
+value `$main` returns.";
+
+/// Exit code for bad arguments and unreadable files
+const USAGE_ERROR: u8 = 2;
+
+/// Prints `dbg` output to stdout
+struct Stdout;
+
+impl Output for Stdout {
+    fn dbg(&mut self, value: Value) {
+        println!("{value}");
+    }
 }
 
-#[derive(Debug)]
-pub enum Type<'a> {
-    I(TypeSize),
-    U(TypeSize),
-    F(TypeSize),
-    Ref(&'a str),
-    Unresolved(&'a str),
+/// How diagnostics are printed
+#[derive(Clone, Copy)]
+enum Format {
+    /// With the source lines they point to, maybe coloured
+    Human {
+        color: bool,
+    },
+    Json,
 }
 
-#[derive(Debug)]
-pub enum AstNode<'a> {
-    Err(ParserErr, Range),
-    Local(&'a str, Range),
-    Global(&'a str, Range),
-    Const(Type<'a>, &'a str, Range),
-    CPtrOffset(Type<'a>, &'a str, Range),
-    SPtrOffset(Type<'a>, Vec<&'a str>, Range),
-    Cpy(Box<AstNode<'a>>, Range),
-    Add(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
-    Sub(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
-    Div(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
-    Mul(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
-    Call(
-        Type<'a>,
-        Box<AstNode<'a>>,
-        Vec<(Type<'a>, AstNode<'a>)>,
-        Range,
-    ),
-    Ret(Type<'a>, Box<AstNode<'a>>, Range),
-    Stalloc(Type<'a>, Box<AstNode<'a>>, Range),
-    Ptroffset(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
-    Load(Type<'a>, Box<AstNode<'a>>, Range),
-    Store(Type<'a>, Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
-    Dbg(Type<'a>, Box<AstNode<'a>>, Range),
-    Equals(Box<AstNode<'a>>, Box<AstNode<'a>>, Range),
+/// A module's text and where it came from
+struct Source {
+    name: String,
+    text: String,
+    format: Format,
 }
 
-impl AstNode<'_> {
-    pub fn span(&self) -> Range {
-        match self {
-            AstNode::Local(_, r) => r.clone(),
-            AstNode::Global(_, r) => r.clone(),
-            AstNode::Const(_, _, r) => r.clone(),
-            AstNode::CPtrOffset(_, _, r) => r.clone(),
-            AstNode::SPtrOffset(_, _, r) => r.clone(),
-            AstNode::Cpy(_, r) => r.clone(),
-            AstNode::Add(_, _, _, r) => r.clone(),
-            AstNode::Sub(_, _, _, r) => r.clone(),
-            AstNode::Div(_, _, _, r) => r.clone(),
-            AstNode::Mul(_, _, _, r) => r.clone(),
-            AstNode::Call(_, _, _, r) => r.clone(),
-            AstNode::Ret(_, _, r) => r.clone(),
-            AstNode::Stalloc(_, _, r) => r.clone(),
-            AstNode::Ptroffset(_, _, _, r) => r.clone(),
-            AstNode::Load(_, _, r) => r.clone(),
-            AstNode::Store(_, _, _, r) => r.clone(),
-            AstNode::Dbg(_, _, r) => r.clone(),
-            AstNode::Equals(_, _, r) => r.clone(),
-            AstNode::Err(_, r) => r.clone(),
+impl Source {
+    /// Prints `diags` to stderr, whether one of them is an error
+    fn report(&self, diags: &[Diagnostic]) -> bool {
+        for d in diags {
+            match self.format {
+                Format::Human { color } => eprint!("{}", render(d, &self.text, &self.name, color)),
+                Format::Json => eprintln!("{}", to_json(d, &self.text, &self.name)),
+            }
         }
+        diags.iter().any(Diagnostic::is_error)
     }
+}
 
-    pub fn is_var(&self) -> bool {
-        matches!(self, AstNode::Local(..) | AstNode::Global(..))
+/// Reads FILE, or stdin for `None` and `-`
+fn read_source(file: Option<&str>, format: Format) -> Result<Source, ExitCode> {
+    let (name, res) = match file {
+        None | Some("-") => {
+            let mut src = String::new();
+            (
+                "<stdin>",
+                std::io::stdin().read_to_string(&mut src).map(|_| src),
+            )
+        }
+        Some(file) => (file, std::fs::read_to_string(file)),
+    };
+    match res {
+        Ok(text) => Ok(Source {
+            name: name.into(),
+            text,
+            format,
+        }),
+        Err(e) => {
+            eprintln!("can't read {name}: {e}");
+            Err(ExitCode::from(USAGE_ERROR))
+        }
     }
 }
 
-pub fn display_parse_err(x: ParserErr, src: &str, stream: &[Token]) {
-    // ok so basically we need to get the line that the gobbled indexes into
-    let mut lines = src.lines();
-    let mut count = 0;
-    let mut diff = 0;
-    let mut line = None;
-    let mut prevline;
-    // gobbled is in token count. lets get it in chars
-    let mut gobbled = x.end_idx;
-    loop {
-        prevline = line;
-        line = lines.next();
-        if line.is_none() {
-            break;
-        }
-        diff = gobbled - count;
-        count += line.as_ref().unwrap().len();
-        if count > gobbled {
-            break;
+/// `$main`, the first one if there are several
+fn main_function<'m, 'a>(module: &'m Module<'a>) -> Option<&'m Function<'a>> {
+    module.items.iter().find_map(|x| match x {
+        Item::Function(f) if f.name == "main" => Some(f),
+        _ => None,
+    })
+}
+
+/// Diagnostics of every check a parsed module goes through before it runs
+fn check_all(module: &Module) -> Vec<Diagnostic> {
+    let mut diags = verify_module(module);
+    diags.extend(resolve_module(module).1);
+    diags.extend(check_module(module).1);
+    diags.extend(compute_layouts(module, Target::default()).1);
+    if let Some(main) = main_function(module) {
+        if let (Some(first), Some(last)) = (main.params.first(), main.params.last()) {
+            diags.push(
+                Diagnostic::error(
+                    "`$main` can't take parameters",
+                    first.span.start..last.span.end,
+                )
+                .with_code("verify"),
+            );
         }
     }
-    if let Some(l) = prevline {
-        eprintln!("{}", l);
+    diags
+}
+
+/// `code` as the exit code of the process, an error if it doesn't fit in one
+fn exit_code<T: TryInto<u8> + Display + Copy>(src: &Source, code: T, span: Range) -> ExitCode {
+    match code.try_into() {
+        Ok(code) => ExitCode::from(code),
+        Err(_) => {
+            src.report(&[Diagnostic::error(
+                format!("exit code {code} is outside of 0 to 255"),
+                span,
+            )]);
+            ExitCode::FAILURE
+        }
     }
-    eprintln!("{}", line.unwrap());
-    let mut ptr = " ".repeat(diff);
-    ptr.push('^');
-    ptr.push_str("  Expected: ");
-    ptr.push_str(&x.expected);
-    eprintln!("{}", ptr);
-    if let Some((t, e)) = x.next {
-        eprintln!("Next token is {t:?} ({e})");
+}
+
+/// Parses and checks `src`, calls `f` with the module if there are no errors
+fn with_module(src: &Source, f: impl FnOnce(&Module) -> ExitCode) -> ExitCode {
+    match parse_module(&src.text) {
+        Ok(module) if !src.report(&check_all(&module)) => f(&module),
+        Ok(_) => ExitCode::FAILURE,
+        Err(errs) => {
+            src.report(&errs);
+            ExitCode::FAILURE
+        }
     }
 }
 
-#[allow(clippy::type_complexity)]
-pub fn path<'a>(
-) -> BoxedParser<'a, &'a [Token<'a>], Vec<(std::ops::Range<usize>, &'a str)>, ParserErr> {
-    tok(Tok::Ident)
-        .map_range(|x, r| (r, x))
-        .repeated_sep(tok(Tok::Comma))
+/// The only FILE argument, an error if there is more than one
+fn single_file(files: &[String]) -> Result<Option<&str>, ExitCode> {
+    match files {
+        [] => Ok(None),
+        [file] => Ok(Some(file)),
+        _ => Err(usage("expected at most one FILE")),
+    }
 }
 
-pub fn atom<'a>() -> BoxedParser<'a, &'a [Token<'a>], AstNode<'a>, ParserErr> {
-    let local = tok(Tok::Ampersand)
-        .chain(tok(Tok::Ident))
-        .map_range(|x, r| AstNode::Local(x.1, r));
-    let global = tok(Tok::Dollar)
-        .chain(tok(Tok::Ident))
-        .map_range(|x, r| AstNode::Global(x.1, r));
-    let cst = tok(Tok::Bang)
-        .ignore_then(tok(Tok::Ident).delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)))
-        .chain(tok(Tok::Number))
-        .map_range(|x, r| AstNode::Const(Type::Unresolved(x.0), x.1, r));
-    let cptroffset = reserved("cptroffset")
-        .ignore_then(tok(Tok::Ident))
-        .then_ignore(tok(Tok::Comma))
-        .chain(tok(Tok::Number))
-        .map_range(|x, r| AstNode::CPtrOffset(Type::Unresolved(x.0), x.1, r));
+fn usage(message: &str) -> ExitCode {
+    eprintln!("{message}\n\n{USAGE}");
+    ExitCode::from(USAGE_ERROR)
+}
 
-    let sptroffset = reserved("sptroffset")
-        .ignore_then(tok(Tok::Ident))
-        .then_ignore(tok(Tok::Comma))
-        .chain(path())
-        .map_range(|x, r| {
-            AstNode::SPtrOffset(
-                Type::Unresolved(x.0),
-                x.1.into_iter().map(|y| y.1).collect(),
-                r,
-            )
-        });
-    local.or(global).or(cst).or(cptroffset).or(sptroffset)
+/// Splits arguments into options with their values and the remaining files
+struct Args {
+    flags: Vec<String>,
+    values: Vec<(String, String)>,
+    files: Vec<String>,
 }
 
-pub fn op<'a>(standalone: bool) -> BoxedParser<'a, &'a [Token<'a>], AstNode<'a>, ParserErr> {
-    macro_rules! binop {
-        ($x:ident $y:ident) => {
-            let $x = reserved(stringify!($x))
-                .ignore_then(
-                    tok(Tok::Ident)
-                        .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket))
-                        .map(Type::Unresolved),
-                )
-                .chain(atom().then_ignore(tok(Tok::Comma)).chain(atom()))
-                .map_range(|x, r| AstNode::$y(x.0, Box::new(x.1 .0), Box::new(x.1 .1), r));
+impl Args {
+    /// `with_value` lists the options that take a value
+    fn parse(args: &[String], with_value: &[&str]) -> Result<Args, ExitCode> {
+        let mut parsed = Args {
+            flags: Vec::new(),
+            values: Vec::new(),
+            files: Vec::new(),
         };
+        let with_value = [with_value, &["--color", "--message-format"]].concat();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                parsed.files.push(arg.clone());
+            } else if let Some((name, value)) = arg.split_once('=') {
+                parsed.values.push((name.into(), value.into()));
+            } else if with_value.contains(&arg.as_str()) {
+                let Some(value) = args.next() else {
+                    return Err(usage(&format!("{arg} needs a value")));
+                };
+                parsed.values.push((arg.clone(), value.clone()));
+            } else {
+                parsed.flags.push(arg.clone());
+            }
+        }
+        Ok(parsed)
     }
 
-    binop!(add Add);
-    binop!(sub Sub);
-    binop!(div Div);
-    binop!(mul Mul);
-    let cpy = reserved("cpy")
-        .ignore_then(atom())
-        .map_range(|x, r| AstNode::Cpy(Box::new(x), r));
-    let call = reserved("call")
-        .ignore_then(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)),
-        )
-        .chain(atom())
-        .chain(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .chain(atom())
-                .repeated_sep(tok(Tok::Comma)),
-        )
-        .map_range(|x, r| AstNode::Call(x.0 .0, Box::new(x.0 .1), x.1, r));
-    let ret = reserved("ret")
-        .ignore_then(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)),
-        )
-        .chain(atom())
-        .map_range(|x, r| AstNode::Ret(x.0, Box::new(x.1), r));
-    let stalloc = reserved("stalloc")
-        .ignore_then(tok(Tok::Ident).map(Type::Unresolved))
-        .then_ignore(reserved("times"))
-        .chain(atom())
-        .map_range(|x, r| AstNode::Stalloc(x.0, Box::new(x.1), r));
-    let ptroffset = reserved("ptroffset")
-        .ignore_then(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)),
-        )
-        .then_ignore(reserved("ptr"))
-        .chain(atom().chain(atom()))
-        .map_range(|x, r| AstNode::Ptroffset(x.0, Box::new(x.1 .0), Box::new(x.1 .1), r));
-    let load = reserved("load")
-        .ignore_then(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)),
-        )
-        .then_ignore(reserved("ptr"))
-        .chain(atom())
-        .map_range(|x, r| AstNode::Load(x.0, Box::new(x.1), r));
-    let store = reserved("store")
-        .ignore_then(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)),
-        )
-        .then_ignore(reserved("ptr"))
-        .chain(atom().then_ignore(tok(Tok::Comma)).chain(atom()))
-        .map_range(|x, r| AstNode::Store(x.0, Box::new(x.1 .0), Box::new(x.1 .1), r));
-    let dbg = reserved("dbg")
-        .ignore_then(
-            tok(Tok::Ident)
-                .map(Type::Unresolved)
-                .delimited(tok(Tok::OpenBracket), tok(Tok::CloseBracket)),
-        )
-        .chain(atom())
-        .map_range(|x, r| AstNode::Dbg(x.0, Box::new(x.1), r));
-    if !standalone {
-        call.or(stalloc)
-            .or(ptroffset)
-            .or(load)
-            .or(add)
-            .or(sub)
-            .or(div)
-            .or(mul)
-            .or(cpy)
-    } else {
-        call.or(ret).or(store).or(dbg)
+    /// From `--color` and `--message-format`, which every command takes
+    fn format(&self) -> Result<Format, ExitCode> {
+        let color = match self.value("--color") {
+            None | Some("auto") => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+            Some("always") => true,
+            Some("never") => false,
+            Some(when) => {
+                return Err(usage(&format!(
+                    "--color needs auto, always or never, not {when}"
+                )))
+            }
+        };
+        match self.value("--message-format") {
+            None | Some("human") => Ok(Format::Human { color }),
+            Some("json") => Ok(Format::Json),
+            Some(format) => Err(usage(&format!(
+                "--message-format needs human or json, not {format}"
+            ))),
+        }
     }
-}
 
-pub fn stmt<'a>() -> BoxedParser<'a, &'a [Token<'a>], AstNode<'a>, ParserErr> {
-    let justop = op(true);
-    let var = atom().check(|x| {
-        if x.is_var() {
-            None
-        } else {
-            Some("Variable".to_owned())
+    /// Fails on options outside of `known`
+    fn only(&self, known: &[&str]) -> Result<(), ExitCode> {
+        let names = self.flags.iter().chain(self.values.iter().map(|x| &x.0));
+        let known = [known, &["--color", "--message-format"]].concat();
+        let mut unknown = names.filter(|x| !known.contains(&x.as_str()));
+        match unknown.next() {
+            Some(name) => Err(usage(&format!("unknown option {name}"))),
+            None => Ok(()),
         }
-    });
-    let eq = var
-        .then_ignore(tok(Tok::Equals))
-        .chain(op(false))
-        .map_range(|x, r| AstNode::Equals(Box::new(x.0), Box::new(x.1), r));
-    justop.or(eq)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|x| x == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|x| x.0 == name)
+            .map(|x| x.1.as_str())
+    }
+}
+
+fn check(args: &Args) -> Result<ExitCode, ExitCode> {
+    args.only(&[])?;
+    let src = read_source(single_file(&args.files)?, args.format()?)?;
+    Ok(with_module(&src, |_| ExitCode::SUCCESS))
 }
 
-fn main() {
-    let src = r#"&num1 = cpy !(u32)9
-    &num2 = cpy r!(u32)11
-    &result = add(u32) &num1, &num2
-    dbg(u32) &result
+fn run(args: &Args) -> Result<ExitCode, ExitCode> {
+    args.only(&["--checked", "--fuel"])?;
+    let fuel = match args.value("--fuel") {
+        Some(n) => Some(
+            n.parse()
+                .map_err(|_| usage(&format!("--fuel needs a number, not {n}")))?,
+        ),
+        None => None,
+    };
+    let src = read_source(single_file(&args.files)?, args.format()?)?;
+    Ok(with_module(&src, |module| {
+        let Some(main) = main_function(module) else {
+            let end = src.text.trim_end().len();
+            let d = Diagnostic::error("`$main` function not found", end..end)
+                .with_help("`run` starts at `fn $main() () { ... }`");
+            src.report(&[d]);
+            return ExitCode::FAILURE;
+        };
+        let mut interp = Interpreter::new(module, Stdout);
+        interp.checked = args.flag("--checked");
+        interp.limits.fuel = fuel;
+        let res = interp.run_main();
+        let leaks: Vec<Diagnostic> = interp.leaks().into_iter().map(Into::into).collect();
+        src.report(&leaks);
+        match res {
+            Ok(Some(Value::I(v, _))) => exit_code(&src, v, main.ret_span.clone()),
+            Ok(Some(Value::U(v, _))) => exit_code(&src, v, main.ret_span.clone()),
+            Ok(_) => ExitCode::SUCCESS,
+            Err(Trap {
+                kind: TrapKind::Exit(code),
+                span,
+            }) => exit_code(&src, code, span),
+            Err(trap) => {
+                src.report(&[trap.into()]);
+                ExitCode::FAILURE
+            }
+        }
+    }))
+}
 
-"#;
-    let tl = Tok::lexer(src);
-    let ts = TokenStream::new(tl);
+/// Rewrites the files in canonical form, or formats stdin to stdout
+fn fmt(args: &Args) -> Result<ExitCode, ExitCode> {
+    args.only(&[])?;
+    let message_format = args.format()?;
+    let format = |src: &Source| match parse_module(&src.text) {
+        Ok(module) => Some(print_module_with_comments(&module, &src.text)),
+        Err(errs) => {
+            src.report(&errs);
+            None
+        }
+    };
+    if args.files.is_empty() || args.files == ["-"] {
+        let src = read_source(None, message_format)?;
+        let Some(out) = format(&src) else {
+            return Ok(ExitCode::FAILURE);
+        };
+        print!("{out}");
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.files.iter().any(|x| x == "-") {
+        return Err(usage("`-` can't be formatted together with files"));
+    }
+    let mut code = ExitCode::SUCCESS;
+    for file in &args.files {
+        let src = match read_source(Some(file), message_format) {
+            Ok(src) => src,
+            Err(e) => {
+                code = e;
+                continue;
+            }
+        };
+        match format(&src) {
+            Some(out) if out != src.text => {
+                if let Err(e) = std::fs::write(file, out) {
+                    eprintln!("can't write {file}: {e}");
+                    code = ExitCode::from(USAGE_ERROR);
+                }
+            }
+            Some(_) => (),
+            None => code = ExitCode::FAILURE,
+        }
+    }
+    Ok(code)
+}
 
-    let patom = stmt().repeated().eoi().parse(ts.slice());
-    if let Err(x) = patom {
-        display_parse_err(x, src, ts.slice());
-    } else {
-        dbg!(patom.unwrap());
+fn emit(args: &Args) -> Result<ExitCode, ExitCode> {
+    args.only(&["--target"])?;
+    let Some(target) = args.value("--target") else {
+        return Err(usage("emit needs --target"));
+    };
+    if !["nir", "ir"].contains(&target) {
+        return Err(usage(&format!("unknown target {target}")));
     }
+    let src = read_source(single_file(&args.files)?, args.format()?)?;
+    Ok(with_module(&src, |module| {
+        match target {
+            "nir" => print!("{}", print_module(module)),
+            _ => {
+                let (ir, diags) = lower_module(module);
+                match ir {
+                    Some(ir) => print!("{}", print_ir(&ir)),
+                    None => {
+                        src.report(&diags);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+        }
+        ExitCode::SUCCESS
+    }))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(command) = args.first() else {
+        return usage("missing command");
+    };
+    let parsed = match command.as_str() {
+        "-h" | "--help" | "help" => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        "check" => Args::parse(&args[1..], &[]),
+        "run" => Args::parse(&args[1..], &["--fuel"]),
+        "fmt" => Args::parse(&args[1..], &[]),
+        "emit" => Args::parse(&args[1..], &["--target"]),
+        _ => return usage(&format!("unknown command {command}")),
+    };
+    let res = parsed.and_then(|args| match command.as_str() {
+        "check" => check(&args),
+        "run" => run(&args),
+        "fmt" => fmt(&args),
+        _ => emit(&args),
+    });
+    res.unwrap_or_else(|code| code)
 }
@@ -1,11 +1,12 @@
 use alloc::{
     borrow::ToOwned,
     boxed::Box,
-    format,
+    collections::BTreeSet,
     string::{String, ToString},
     vec,
     vec::Vec,
 };
+use core::fmt::{self, Display, Formatter};
 
 use crate::Tok;
 pub type Range = core::ops::Range<usize>;
@@ -14,10 +15,53 @@ pub trait Parser<I, O, E> {
     fn parse(&self, input: I) -> Result<(I, O, Range), E>;
 }
 
-#[derive(Debug)]
+/// Something the parser would have accepted where it failed
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Expected {
+    /// A named piece of grammar like `instruction`, see [`BoxedParser::labelled`]
+    Label(String),
+    /// An identifier spelled like this, like `fn`
+    Keyword(String),
+    /// A token of this kind
+    Token(Tok),
+}
+
+impl Display for Expected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Label(label) => f.write_str(label),
+            Expected::Keyword(word) => write!(f, "`{word}`"),
+            Expected::Token(t) => f.write_str(match t {
+                Tok::Err => "a valid token",
+                Tok::Dollar => "`$`",
+                Tok::Ampersand => "`&`",
+                Tok::Caret => "`^`",
+                Tok::Comma => "`,`",
+                Tok::Dot => "`.`",
+                Tok::Bang => "`!`",
+                Tok::Star => "`*`",
+                Tok::Semicolon => "`;`",
+                Tok::HeaderStart => "`--NIR--`",
+                Tok::HeaderEnd => "`--END--`",
+                Tok::Ident => "identifier",
+                Tok::Str => "string",
+                Tok::OpenBracket => "`(`",
+                Tok::CloseBracket => "`)`",
+                Tok::OpenBrace => "`{`",
+                Tok::CloseBrace => "`}`",
+                Tok::Number => "number",
+                Tok::Equals => "`=`",
+                Tok::Eof => "end of file",
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ParserErr {
     pub end_idx: usize,
-    pub expected: String,
+    /// Everything that would have been accepted at `end_idx`
+    pub expected: BTreeSet<Expected>,
     pub next: Option<(Tok, String)>,
 }
 
@@ -56,18 +100,43 @@ impl<'a, A, B, C> BoxedParser<'a, A, B, C> {
     }
 }
 
+/// Range covering both `a` and `b`, empty ranges from optional parsers don't extend it
+fn join(a: Range, b: Range) -> Range {
+    if b.is_empty() {
+        a
+    } else if a.is_empty() {
+        b
+    } else {
+        a.start..b.end
+    }
+}
+
 pub trait SliceHelper<T> {
     fn finished(&self) -> bool;
     fn first(&self) -> &T;
+    /// Where the next token starts
+    fn offset(&self) -> usize;
 }
 
-impl<T> SliceHelper<T> for &[T] {
+impl<'s> SliceHelper<Token<'s>> for &[Token<'s>] {
+    /// Token streams end with a [`Tok::Eof`]
     fn finished(&self) -> bool {
-        self.is_empty()
+        self.is_empty() || self[0].0 == Tok::Eof
     }
-    fn first(&self) -> &T {
+    fn first(&self) -> &Token<'s> {
         &self[0]
     }
+    fn offset(&self) -> usize {
+        <[Token]>::first(self).map_or(0, |t| t.1.start)
+    }
+}
+
+/// The token an error is about, `None` at the end of the input
+fn next_token(input: &[Token]) -> Option<(Tok, String)> {
+    input
+        .first()
+        .filter(|t| t.0 != Tok::Eof)
+        .map(|t| (t.0, t.2.to_owned()))
 }
 
 // combinators
@@ -94,6 +163,17 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
             sp.map(|(i, o, z)| (i, f(o, z.clone()), z))
         }))
     }
+    pub fn try_map<F, O2>(self, f: F) -> BoxedParser<'a, I, O2, ParserErr>
+    where
+        F: Fn(O, Range) -> Result<O2, ParserErr> + 'a,
+        I: 'a,
+        O: 'a,
+    {
+        BoxedParser(Box::new(move |i: I| {
+            let (i, o, z) = self.parse(i)?;
+            Ok((i, f(o, z.clone())?, z))
+        }))
+    }
     pub fn chain<O2>(
         self,
         ting: impl Parser<I, O2, ParserErr> + 'a,
@@ -106,7 +186,7 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
             let sp = self.parse(i)?;
             // TODO map_err to make sure sp2 error gobbled includes what sp parsed
             let sp2 = ting.parse(sp.0)?;
-            Ok((sp2.0, (sp.1, sp2.1), sp.2.start..sp2.2.end))
+            Ok((sp2.0, (sp.1, sp2.1), join(sp.2, sp2.2)))
         }))
     }
 
@@ -134,23 +214,22 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
         self.chain(ting).map(|x| x.0)
     }
 
-    pub fn check<F>(self, check: F) -> BoxedParser<'a, I, O, ParserErr>
+    /// Fails if `check` rejects the output, expecting what it returns where the output starts
+    pub fn check<'s, F>(self, check: F) -> BoxedParser<'a, I, O, ParserErr>
     where
-        I: 'a,
+        I: SliceHelper<Token<'s>> + Clone + 'a,
         O: 'a,
         F: Fn(&O) -> Option<String> + 'a,
     {
         BoxedParser(Box::new(move |i: I| {
-            let sp = self.parse(i)?;
-            let res = check(&sp.1);
-            if let Some(res) = res {
-                Err(ParserErr {
-                    end_idx: sp.2.end,
-                    expected: res,
-                    next: None,
-                })
-            } else {
-                Ok(sp)
+            let sp = self.parse(i.clone())?;
+            match check(&sp.1) {
+                Some(res) => Err(ParserErr {
+                    end_idx: i.offset(),
+                    expected: [Expected::Label(res)].into(),
+                    next: Some((i.first().0, i.first().2.to_owned())),
+                }),
+                None => Ok(sp),
             }
         }))
     }
@@ -165,19 +244,20 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
             if selfparse.is_ok() {
                 selfparse
             } else {
-                ting.parse(i).map_err(|e| {
-                    let sp = unsafe { selfparse.unwrap_err_unchecked() };
+                ting.parse(i).map_err(|mut e| {
+                    let mut sp = unsafe { selfparse.unwrap_err_unchecked() };
                     #[allow(clippy::comparison_chain)]
                     if e.end_idx > sp.end_idx {
                         e
                     } else if sp.end_idx > e.end_idx {
-                        return sp;
+                        sp
                     } else {
-                        return ParserErr {
+                        e.expected.append(&mut sp.expected);
+                        ParserErr {
                             end_idx: e.end_idx,
-                            expected: e.expected + ", " + &sp.expected,
+                            expected: e.expected,
                             next: e.next.or(sp.next),
-                        };
+                        }
                     }
                 })
             }
@@ -200,12 +280,18 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
             let mut matched = false;
             let mut ve = vec![];
             let mut parsed;
+            // state before the last separator, a separator is only consumed if an item follows it
+            let mut beforesep = None;
             loop {
                 parsed = self.parse(input.clone());
                 if parsed.is_err() {
                     if !matched {
                         return Err(unsafe { parsed.unwrap_err_unchecked() });
                     }
+                    if let Some((i, end)) = beforesep {
+                        input = i;
+                        rangeend = end;
+                    }
                     break;
                 }
                 matched = true;
@@ -216,13 +302,14 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
                 rangeend = Some(x.2.end);
                 input = x.0;
                 ve.push(x.1);
-                if sep.is_some() {
-                    let sp = sep.as_ref().unwrap().parse(input.clone());
+                if let Some(sep) = &sep {
+                    let sp = sep.parse(input.clone());
                     if sp.is_err() {
                         break;
                     }
 
                     let sp = unsafe { sp.unwrap_unchecked() };
+                    beforesep = Some((input.clone(), rangeend));
                     rangeend = Some(sp.2.end);
                     input = sp.0;
                 }
@@ -280,9 +367,24 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
         }))
     }
 
-    pub fn eoi(self) -> BoxedParser<'a, I, O, ParserErr>
+    /// Never fails, an absent match has an empty range at the next token
+    pub fn optional<'s>(self) -> BoxedParser<'a, I, Option<O>, ParserErr>
+    where
+        I: SliceHelper<Token<'s>> + Clone + 'a,
+        O: 'a,
+    {
+        BoxedParser(Box::new(move |i: I| match self.parse(i.clone()) {
+            Ok((i, o, r)) => Ok((i, Some(o), r)),
+            Err(_) => {
+                let at = i.offset();
+                Ok((i, None, at..at))
+            }
+        }))
+    }
+
+    pub fn eoi<'s>(self) -> BoxedParser<'a, I, O, ParserErr>
     where
-        I: SliceHelper<Token<'a>> + 'a,
+        I: SliceHelper<Token<'s>> + 'a,
         O: 'a,
     {
         BoxedParser(Box::new(move |i: I| {
@@ -291,9 +393,9 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
                 Ok(sp)
             } else {
                 Err(ParserErr {
-                    end_idx: sp.2.end,
-                    expected: "EOF".to_owned(),
-                    next: Some((sp.0.first().0.clone(), sp.0.first().2.to_owned())),
+                    end_idx: sp.0.offset(),
+                    expected: [Expected::Token(Tok::Eof)].into(),
+                    next: Some((sp.0.first().0, sp.0.first().2.to_owned())),
                 })
             }
         }))
@@ -302,6 +404,57 @@ impl<'a, I, O> BoxedParser<'a, I, O, ParserErr> {
 
 pub type Token<'a> = (Tok, logos::Span, &'a str);
 
+impl<'t, 's: 't, O: 't> BoxedParser<'t, &'t [Token<'s>], O, ParserErr> {
+    /// Names what this parser accepts, so a failure before it got anywhere expects `label`
+    /// instead of everything it could have started with
+    pub fn labelled(self, label: &'static str) -> BoxedParser<'t, &'t [Token<'s>], O, ParserErr> {
+        BoxedParser(Box::new(move |i: &'t [Token<'s>]| {
+            self.parse(i).map_err(|mut e| {
+                if i.first().is_none_or(|t| e.end_idx == t.1.start) {
+                    e.expected = [Expected::Label(label.to_owned())].into();
+                }
+                e
+            })
+        }))
+    }
+
+    /// On failure skips ahead to the next place `sync` accepts and returns `err` of the error
+    /// and the skipped range instead, so parsing can go on after a mistake. `sync` gets the
+    /// token before the place, if any, and the rest from there. Braces are skipped in pairs,
+    /// `sync` is only tried outside of them. Still fails if there is nothing left or `sync`
+    /// accepts the start already
+    pub fn recover<S, F>(self, sync: S, err: F) -> BoxedParser<'t, &'t [Token<'s>], O, ParserErr>
+    where
+        S: Fn(Option<&Token<'s>>, &'t [Token<'s>]) -> bool + 't,
+        F: Fn(ParserErr, Range) -> O + 't,
+    {
+        BoxedParser(Box::new(move |i: &'t [Token<'s>]| {
+            let e = match self.parse(i) {
+                Ok(x) => return Ok(x),
+                Err(e) => e,
+            };
+            if i.finished() || sync(None, i) {
+                return Err(e);
+            }
+            let mut depth = 0usize;
+            let mut n = 0;
+            loop {
+                match i[n].0 {
+                    Tok::OpenBrace => depth += 1,
+                    Tok::CloseBrace => depth = depth.saturating_sub(1),
+                    _ => (),
+                }
+                n += 1;
+                if (&i[n..]).finished() || (depth == 0 && sync(Some(&i[n - 1]), &i[n..])) {
+                    break;
+                }
+            }
+            let skipped = i[0].1.start..i[n - 1].1.end;
+            Ok((&i[n..], err(e, skipped.clone()), skipped))
+        }))
+    }
+}
+
 pub struct TokenStream<'a>(Vec<Token<'a>>);
 
 impl<'a> TokenStream<'a> {
@@ -311,6 +464,8 @@ impl<'a> TokenStream<'a> {
             let i = i.unwrap_or(Tok::Err);
             v.push((i, lex.span(), lex.slice()));
         }
+        let end = v.last().map_or(0, |t: &Token| t.1.end);
+        v.push((Tok::Eof, end..end, ""));
         TokenStream(v)
     }
 
@@ -319,66 +474,48 @@ impl<'a> TokenStream<'a> {
     }
 }
 
-pub fn reserved<'a, 'b>(tomatch: &'a str) -> BoxedParser<'b, &'b [Token<'b>], (), ParserErr>
-where
-    'a: 'b,
-{
-    BoxedParser::new(move |input: &'b [Token<'b>]| match input.first().cloned() {
+pub fn reserved<'t, 's: 't>(tomatch: &'t str) -> BoxedParser<'t, &'t [Token<'s>], (), ParserErr> {
+    BoxedParser::new(move |input: &'t [Token<'s>]| match input.first().cloned() {
         Some((Tok::Ident, sp, st)) if st == tomatch => Ok((&input[1..], (), sp)),
         _ => Err(ParserErr {
-            end_idx: input.first().map_or(0, |x| x.1.start),
-            expected: tomatch.to_string(),
-            next: if input.first().is_some() {
-                Some((
-                    input.first().unwrap().0.clone(),
-                    input.first().unwrap().2.to_owned(),
-                ))
-            } else {
-                None
-            },
+            end_idx: input.offset(),
+            expected: [Expected::Keyword(tomatch.to_string())].into(),
+            next: next_token(input),
         }),
     })
 }
 
-pub fn tok<'a>(tomatch: Tok) -> BoxedParser<'a, &'a [Token<'a>], &'a str, ParserErr> {
-    BoxedParser::new(move |input: &'a [Token<'a>]| match input.first().cloned() {
+pub fn tok<'t, 's: 't>(tomatch: Tok) -> BoxedParser<'t, &'t [Token<'s>], &'s str, ParserErr> {
+    BoxedParser::new(move |input: &'t [Token<'s>]| match input.first().cloned() {
         Some((x, sp, st)) if x == tomatch => Ok((&input[1..], st, sp)),
         _ => Err(ParserErr {
-            end_idx: input.first().map_or(0, |x| x.1.start),
-            expected: format!("{:?}", tomatch),
-            next: if input.first().is_some() {
-                Some((
-                    input.first().unwrap().0.clone(),
-                    input.first().unwrap().2.to_owned(),
-                ))
-            } else {
-                None
-            },
+            end_idx: input.offset(),
+            expected: [Expected::Token(tomatch)].into(),
+            next: next_token(input),
         }),
     })
 }
 
-pub fn match_until<'a, O>(
-    p2: BoxedParser<'a, &'a [Token<'a>], O, ParserErr>,
-) -> BoxedParser<'a, &'a [Token<'a>], O, ParserErr>
+pub fn match_until<'t, 's: 't, O>(
+    p2: BoxedParser<'t, &'t [Token<'s>], O, ParserErr>,
+) -> BoxedParser<'t, &'t [Token<'s>], O, ParserErr>
 where
-    O: 'a,
+    O: 't,
 {
-    BoxedParser(Box::new(move |i: &'a [Token]| {
+    BoxedParser(Box::new(move |i: &'t [Token<'s>]| {
         let mut eidx = 0;
-        let mut expected = "?".to_string();
+        let mut expected = BTreeSet::new();
         for j in 0..i.len() {
             eidx = i[j].1.end;
             let pp = p2.parse(&i[j..]);
-            if pp.is_ok() {
-                return Ok(pp.unwrap());
-            } else {
-                expected = pp.map(|_| ()).unwrap_err().expected;
+            match pp {
+                Ok(pp) => return Ok(pp),
+                Err(e) => expected = e.expected,
             }
         }
         Err(ParserErr {
             end_idx: eidx,
-            expected: expected,
+            expected,
             next: None,
         })
     }))
@@ -0,0 +1,705 @@
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::fmt::{self, Display, Formatter, Write};
+
+use logos::Logos;
+
+use crate::ast::{AstNode, Function, Item, Module, StructDef, TypeSize};
+use crate::ir::{self, Callee, InstKind, Terminator, Ty, ValueId};
+use crate::{Range, Tok};
+
+/// Writes `value` as a string literal the lexer reads back unchanged
+fn escape(f: &mut impl Write, value: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '\n' => f.write_str("\\n")?,
+            '\t' => f.write_str("\\t")?,
+            '\r' => f.write_str("\\r")?,
+            '\0' => f.write_str("\\0")?,
+            '\\' => f.write_str("\\\\")?,
+            '"' => f.write_str("\\\"")?,
+            c if c.is_ascii_control() => write!(f, "\\x{:02x}", c as u8)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Comma separated
+fn join<T: Display>(f: &mut Formatter<'_>, items: impl IntoIterator<Item = T>) -> fmt::Result {
+    for (i, x) in items.into_iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{x}")?;
+    }
+    Ok(())
+}
+
+/// Canonical NIR for an atom, instruction or statement
+impl Display for AstNode<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some((op, t, a, b)) = self.binop() {
+            return write!(f, "{}({t}) {a}, {b}", op.name());
+        }
+        if let Some((op, t, a)) = self.unop() {
+            return write!(f, "{}({t}) {a}", op.name());
+        }
+        match self {
+            AstNode::Err(..) => f.write_str("// unparsable"),
+            AstNode::Local(name, _) => write!(f, "&{name}"),
+            AstNode::Global(name, _) => write!(f, "${name}"),
+            AstNode::StrRef(name, _) => write!(f, "^{name}"),
+            AstNode::Const(t, lit, _) => write!(f, "!({t}){lit}"),
+            AstNode::CPtrOffset(t, n, _) => write!(f, "cptroffset {t}, {n}"),
+            AstNode::SPtrOffset(t, path, _) => {
+                write!(f, "sptroffset {t}, ")?;
+                join(f, path)
+            }
+            AstNode::Cpy(a, _) => write!(f, "cpy {a}"),
+            AstNode::Cast(op, to, from, a, _) => write!(f, "{}({to}) {from} {a}", op.name()),
+            AstNode::Call(ret, target, args, _) => {
+                f.write_str("call(")?;
+                if let Some(t) = ret {
+                    write!(f, "{t}")?;
+                }
+                write!(f, ") {target} (")?;
+                join(f, args.iter().map(|(t, a)| format!("{t} {a}")))?;
+                f.write_char(')')
+            }
+            AstNode::Ret(t, a, _) => write!(f, "ret({t}) {a}"),
+            AstNode::RetVoid(_) => f.write_str("ret"),
+            AstNode::Stalloc(t, n, _) => write!(f, "stalloc {t} times {n}"),
+            AstNode::Halloc(t, n, _) => write!(f, "halloc {t} times {n}"),
+            AstNode::Hfree(p, _) => write!(f, "hfree {p}"),
+            AstNode::Hrealloc(t, p, n, _) => write!(f, "hrealloc({t}) ptr {p} times {n}"),
+            AstNode::Ptroffset(t, p, i, _) => write!(f, "ptroffset({t}) ptr {p} {i}"),
+            AstNode::Load(t, p, _) => write!(f, "load({t}) ptr {p}"),
+            AstNode::Store(t, p, v, _) => write!(f, "store({t}) ptr {p}, {v}"),
+            AstNode::Dbg(t, a, _) => write!(f, "dbg({t}) {a}"),
+            AstNode::Equals(target, op, _) => write!(f, "{target} = {op}"),
+            AstNode::Label(l, _) => write!(f, ".{l}"),
+            AstNode::Jmp(l, _) => write!(f, "jmp .{l}"),
+            AstNode::Br(t, c, a, b, _) => write!(f, "br({t}) {c}, .{a}, .{b}"),
+            AstNode::Sys(op, args, _) => {
+                write!(f, "sys {}", op.name())?;
+                if !args.is_empty() {
+                    f.write_char(' ')?;
+                    join(f, args)?;
+                }
+                Ok(())
+            }
+            _ => unreachable!("binary and unary operations are handled above"),
+        }
+    }
+}
+
+/// Formats a module as canonical NIR, which parses back to the same module. Comments are lost,
+/// see [`print_module_with_comments`]
+pub fn print_module(m: &Module) -> String {
+    Printer::new(Vec::new(), None).module(m)
+}
+
+/// Like [`print_module`], but keeps the comments of `src`, the text `m` was parsed from. Each
+/// comment goes in front of the header constant, item or statement that followed it, or at the
+/// end of the line it ended
+pub fn print_module_with_comments(m: &Module, src: &str) -> String {
+    let mut comments = Vec::new();
+    let mut header = None;
+    let mut end = 0;
+    let mut lex = Tok::lexer(src);
+    loop {
+        let next = lex.next();
+        let span = lex.span();
+        // only whitespace and comments are skipped between tokens
+        let gap = if next.is_some() {
+            span.start
+        } else {
+            src.len()
+        };
+        let mut at = end;
+        while at < gap {
+            let rest = &src[at..gap];
+            let Some(skip) = rest.find(|c: char| !c.is_whitespace()) else {
+                break;
+            };
+            let start = at + skip;
+            let len = src[start..gap].find('\n').unwrap_or(gap - start);
+            let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+            comments.push(Comment {
+                start,
+                text: src[start..start + len].trim_end(),
+                own_line: src[line_start..start].trim().is_empty(),
+            });
+            at = start + len;
+        }
+        match next {
+            Some(Ok(Tok::HeaderStart)) => header = Some(span.start..span.start),
+            Some(Ok(Tok::HeaderEnd)) => {
+                if let Some(h) = &mut header {
+                    h.end = span.start;
+                }
+            }
+            Some(_) => (),
+            None => break,
+        }
+        end = span.end;
+    }
+    Printer::new(comments, header).module(m)
+}
+
+/// A comment in the source, with `//` or `;`
+struct Comment<'s> {
+    start: usize,
+    text: &'s str,
+    /// Whether nothing comes before it on its line
+    own_line: bool,
+}
+
+/// Writes a module, with the comments that come before each part of it
+struct Printer<'s> {
+    out: String,
+    comments: Vec<Comment<'s>>,
+    next: usize,
+    /// Where `--NIR--` and `--END--` start in the source
+    header: Option<Range>,
+}
+
+impl<'s> Printer<'s> {
+    fn new(comments: Vec<Comment<'s>>, header: Option<Range>) -> Self {
+        Printer {
+            out: String::new(),
+            comments,
+            next: 0,
+            header,
+        }
+    }
+
+    /// Writes the comments before `offset` that end a line, at the end of the last line
+    fn trailing(&mut self, offset: usize) {
+        while let Some(c) = self.comments.get(self.next) {
+            if c.start >= offset || c.own_line || !self.out.ends_with('\n') {
+                break;
+            }
+            self.out.pop();
+            let _ = writeln!(self.out, " {}", c.text);
+            self.next += 1;
+        }
+    }
+
+    /// Writes the comments before `offset`, those on their own line indented by `indent`
+    fn comments(&mut self, offset: usize, indent: &str) {
+        while let Some(c) = self.comments.get(self.next) {
+            if c.start >= offset {
+                break;
+            }
+            if !c.own_line && self.out.ends_with('\n') {
+                self.out.pop();
+                let _ = writeln!(self.out, " {}", c.text);
+            } else {
+                let _ = writeln!(self.out, "{indent}{}", c.text);
+            }
+            self.next += 1;
+        }
+    }
+
+    fn module(mut self, m: &Module) -> String {
+        let header = self.header.clone();
+        if !m.header.is_empty() {
+            self.comments(header.as_ref().map_or(0, |h| h.start), "");
+            self.out.push_str("--NIR--\n");
+            for c in &m.header {
+                self.comments(c.span.start, "");
+                let _ = write!(self.out, "^{}=", c.name);
+                let _ = escape(&mut self.out, &c.value);
+                self.out.push_str(";\n");
+            }
+            self.comments(header.as_ref().map_or(0, |h| h.end), "");
+            self.out.push_str("--END--\n");
+        }
+        for (i, item) in m.items.iter().enumerate() {
+            let span = match item {
+                Item::Struct(s) => &s.span,
+                Item::Function(f) => &f.span,
+            };
+            self.trailing(span.start);
+            if i > 0 || !m.header.is_empty() {
+                self.out.push('\n');
+            }
+            self.comments(span.start, "");
+            match item {
+                Item::Struct(s) => self.structdef(s),
+                Item::Function(func) => self.function(func),
+            }
+        }
+        self.comments(usize::MAX, "");
+        self.out
+    }
+
+    fn structdef(&mut self, s: &StructDef) {
+        let out = &mut self.out;
+        if s.packed {
+            out.push_str("packed ");
+        }
+        let _ = write!(out, "struct ${} {{", s.name);
+        for (i, field) in s.fields.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            if let Some(name) = field.name {
+                let _ = write!(out, "{name}=");
+            }
+            let _ = write!(out, "{}", field.ty);
+        }
+        out.push_str("}\n");
+    }
+
+    fn function(&mut self, func: &Function) {
+        let out = &mut self.out;
+        let _ = write!(out, "fn ${}(", func.name);
+        if let Some(t) = &func.ret {
+            let _ = write!(out, "{t}");
+        }
+        out.push_str(") (");
+        for (i, p) in func.params.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{} {}", p.ty, p.name);
+        }
+        out.push_str(") {\n");
+        for node in &func.body {
+            self.comments(node.span().start, "    ");
+            // labels stick out of the block they start
+            if !matches!(node, AstNode::Label(..)) {
+                self.out.push_str("    ");
+            }
+            let _ = writeln!(self.out, "{node}");
+        }
+        self.comments(func.span.end, "    ");
+        self.out.push_str("}\n");
+    }
+}
+
+/// A name starting with `base` that no name in `taken` starts with
+fn fresh_prefix<'n>(base: &str, taken: impl Iterator<Item = &'n str> + Clone) -> String {
+    let mut prefix = String::from(base);
+    while taken.clone().any(|n| n.starts_with(prefix.as_str())) {
+        prefix.push('_');
+    }
+    prefix
+}
+
+/// Prints one function of the lowered IR
+struct IrPrinter<'m> {
+    m: &'m ir::Module,
+    f: &'m ir::Function,
+    /// Values that are written to a temporary local, by the name of the local
+    temps: BTreeMap<ValueId, String>,
+    temp_prefix: String,
+    labels: Vec<String>,
+}
+
+impl IrPrinter<'_> {
+    fn ty(&self, t: &Ty) -> String {
+        format!("{}", self.m.ty(t))
+    }
+
+    fn local(&self, l: ir::LocalId) -> &str {
+        self.m.name(self.f.locals[l.index()].name)
+    }
+
+    /// Float constants NIR can't write as a literal
+    fn inexact_float(&self, v: ValueId) -> bool {
+        let inst = self.f.inst(v);
+        let InstKind::Const(bits) = inst.kind else {
+            return false;
+        };
+        let x = match inst.ty {
+            Some(Ty::F(TypeSize::_32)) => f64::from(f32::from_bits(bits as u32)),
+            Some(Ty::F(_)) => f64::from_bits(bits),
+            _ => return false,
+        };
+        !((0.0..9007199254740992.0).contains(&x) && x == (x as u64) as f64)
+            || (x == 0.0 && x.is_sign_negative())
+    }
+
+    /// `v` as an operand
+    fn atom(&self, v: ValueId) -> String {
+        if let Some(name) = self.temps.get(&v) {
+            return format!("&{name}");
+        }
+        let inst = self.f.inst(v);
+        let ty = inst.ty.as_ref().map(|t| self.ty(t)).unwrap_or_default();
+        match &inst.kind {
+            InstKind::Const(bits) => match &inst.ty {
+                Some(Ty::F(TypeSize::_32)) => format!("!({ty}){}", f32::from_bits(*bits as u32)),
+                Some(Ty::F(_)) => format!("!({ty}){}", f64::from_bits(*bits)),
+                // decimal constants of signed types must be positive
+                Some(Ty::I(s)) if *bits >= 1 << (s.bits() - 1) => format!("!({ty}){bits:#x}"),
+                _ => format!("!({ty}){bits}"),
+            },
+            InstKind::Str(id) => format!("^{}", self.m.name(self.m.strings[id.index()].name)),
+            InstKind::ElemOffset(t, n) => format!("cptroffset {}, {n}", self.ty(t)),
+            InstKind::FieldOffset(t, path) => {
+                let mut s = format!("sptroffset {}, ", self.ty(t));
+                let mut cur = t;
+                for (i, idx) in path.iter().enumerate() {
+                    if i > 0 {
+                        s.push_str(", ");
+                    }
+                    let Ty::Struct(id) = cur else { break };
+                    let field = &self.m.structs[id.index()].fields[*idx as usize];
+                    match field.name {
+                        Some(name) => s.push_str(self.m.name(name)),
+                        None => {
+                            let _ = write!(s, "{idx}");
+                        }
+                    }
+                    cur = &field.ty;
+                }
+                s
+            }
+            InstKind::ReadLocal(l) => format!("&{}", self.local(*l)),
+            _ => unreachable!("instructions with side effects are printed as statements"),
+        }
+    }
+
+    /// The instruction without its target
+    fn op(&self, v: ValueId) -> String {
+        let inst = self.f.inst(v);
+        let ty = inst.ty.as_ref().map(|t| self.ty(t)).unwrap_or_default();
+        let a = |v: &ValueId| self.atom(*v);
+        match &inst.kind {
+            InstKind::Const(bits) if self.inexact_float(v) => match inst.ty {
+                Some(Ty::F(TypeSize::_32)) => format!("bitcast(f32) u32 !(u32){bits:#x}"),
+                _ => format!("bitcast(f64) u64 !(u64){bits:#x}"),
+            },
+            InstKind::Const(_)
+            | InstKind::Str(_)
+            | InstKind::ElemOffset(..)
+            | InstKind::FieldOffset(..)
+            | InstKind::ReadLocal(_) => format!("cpy {}", self.atom(v)),
+            InstKind::WriteLocal(_, x) => format!("cpy {}", a(x)),
+            InstKind::Binary(op, t, x, y) => {
+                format!("{}({}) {}, {}", op.name(), self.ty(t), a(x), a(y))
+            }
+            InstKind::Unary(op, t, x) => format!("{}({}) {}", op.name(), self.ty(t), a(x)),
+            InstKind::Cast(op, from, x) => {
+                format!("{}({ty}) {} {}", op.name(), self.ty(from), a(x))
+            }
+            InstKind::Call(callee, args) => {
+                let name = match callee {
+                    Callee::Function(id) => self.m.name(self.m.functions[id.index()].name),
+                    Callee::Host(name) => self.m.name(*name),
+                };
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|x| {
+                        let t = self.f.inst(*x).ty.as_ref().map(|t| self.ty(t));
+                        format!("{} {}", t.unwrap_or_default(), a(x))
+                    })
+                    .collect();
+                format!("call({ty}) ${name} ({})", args.join(", "))
+            }
+            InstKind::Stalloc(t, n) => format!("stalloc {} times {}", self.ty(t), a(n)),
+            InstKind::Halloc(t, n) => format!("halloc {} times {}", self.ty(t), a(n)),
+            InstKind::Hrealloc(t, p, n) => {
+                format!("hrealloc({}) ptr {} times {}", self.ty(t), a(p), a(n))
+            }
+            InstKind::Hfree(p) => format!("hfree {}", a(p)),
+            InstKind::Ptroffset(t, p, i) => {
+                format!("ptroffset({}) ptr {} {}", self.ty(t), a(p), a(i))
+            }
+            InstKind::Load(t, p) => format!("load({}) ptr {}", self.ty(t), a(p)),
+            InstKind::Store(t, p, x) => format!("store({}) ptr {}, {}", self.ty(t), a(p), a(x)),
+            InstKind::Dbg(t, x) => format!("dbg({}) {}", self.ty(t), a(x)),
+            InstKind::Sys(op, args) => {
+                let args: Vec<String> = args.iter().map(a).collect();
+                let sep = if args.is_empty() { "" } else { " " };
+                format!("sys {}{sep}{}", op.name(), args.join(", "))
+            }
+        }
+    }
+
+    fn block(&mut self, out: &mut String, b: &ir::Block) {
+        let insts = &b.insts;
+        let mut uses: BTreeMap<ValueId, Vec<usize>> = BTreeMap::new();
+        for (i, v) in insts.iter().enumerate() {
+            for x in self.f.inst(*v).kind.operands() {
+                uses.entry(x).or_default().push(i);
+            }
+        }
+        let term_operand = match &b.term {
+            Terminator::Ret(Some(v)) | Terminator::Br(v, ..) => Some(*v),
+            _ => None,
+        };
+        if let Some(v) = term_operand {
+            uses.entry(v).or_default().push(insts.len());
+        }
+
+        for (i, v) in insts.iter().enumerate() {
+            let inst = self.f.inst(*v);
+            let used = uses.get(v).map(Vec::as_slice).unwrap_or_default();
+            let temp = format!("{}{}", self.temp_prefix, v.0);
+            match &inst.kind {
+                InstKind::ReadLocal(l) => {
+                    // a read stays an operand unless the local is written before the last use
+                    let last = used.iter().max().copied().unwrap_or(i);
+                    let overwritten = insts[i + 1..last.max(i + 1)].iter().any(
+                        |w| matches!(self.f.inst(*w).kind, InstKind::WriteLocal(m, _) if m == *l),
+                    );
+                    if overwritten {
+                        let _ = writeln!(out, "    &{temp} = cpy &{}", self.local(*l));
+                        self.temps.insert(*v, temp);
+                    }
+                }
+                InstKind::Const(_) if self.inexact_float(*v) => {
+                    let _ = writeln!(out, "    &{temp} = {}", self.op(*v));
+                    self.temps.insert(*v, temp);
+                }
+                InstKind::Const(_)
+                | InstKind::Str(_)
+                | InstKind::ElemOffset(..)
+                | InstKind::FieldOffset(..) => (),
+                InstKind::WriteLocal(l, x) => {
+                    let fused = i > 0 && insts[i - 1] == *x && !self.temps.contains_key(x);
+                    let op = if fused && self.fusable(*x, used_once(&uses, *x)) {
+                        self.op(*x)
+                    } else {
+                        format!("cpy {}", self.atom(*x))
+                    };
+                    let _ = writeln!(out, "    &{} = {op}", self.local(*l));
+                }
+                _ => {
+                    let next = insts.get(i + 1).map(|w| &self.f.inst(*w).kind);
+                    let fuses = matches!(next, Some(InstKind::WriteLocal(_, x)) if x == v)
+                        && used.len() == 1;
+                    if fuses {
+                        continue;
+                    }
+                    if inst.ty.is_none() || (used.is_empty() && is_effect(&inst.kind)) {
+                        let _ = writeln!(out, "    {}", self.op(*v));
+                    } else {
+                        let _ = writeln!(out, "    &{temp} = {}", self.op(*v));
+                        self.temps.insert(*v, temp);
+                    }
+                }
+            }
+        }
+
+        let line = match &b.term {
+            Terminator::Ret(Some(v)) => {
+                let t = self.f.ret.as_ref().map(|t| self.ty(t)).unwrap_or_default();
+                format!("ret({t}) {}", self.atom(*v))
+            }
+            Terminator::Ret(None) => "ret".into(),
+            Terminator::Jmp(t) => format!("jmp .{}", self.labels[t.index()]),
+            Terminator::Br(c, t, e) => {
+                let ty = self.f.inst(*c).ty.as_ref().map(|t| self.ty(t));
+                format!(
+                    "br({}) {}, .{}, .{}",
+                    ty.unwrap_or_default(),
+                    self.atom(*c),
+                    self.labels[t.index()],
+                    self.labels[e.index()]
+                )
+            }
+            // follows `sys exit`, which NIR already treats as the end of the block
+            Terminator::Unreachable => return,
+        };
+        let _ = writeln!(out, "    {line}");
+    }
+
+    /// Whether `v`, written to a local right after it, can be printed as that assignment
+    fn fusable(&self, v: ValueId, once: bool) -> bool {
+        once && !matches!(
+            self.f.inst(v).kind,
+            InstKind::Const(_)
+                | InstKind::Str(_)
+                | InstKind::ElemOffset(..)
+                | InstKind::FieldOffset(..)
+                | InstKind::ReadLocal(_)
+        )
+    }
+}
+
+fn used_once(uses: &BTreeMap<ValueId, Vec<usize>>, v: ValueId) -> bool {
+    uses.get(&v).is_some_and(|u| u.len() == 1)
+}
+
+/// Instructions worth keeping as a statement when their value isn't used
+fn is_effect(kind: &InstKind) -> bool {
+    matches!(kind, InstKind::Call(..) | InstKind::Sys(..))
+}
+
+/// Formats lowered IR, e.g. from the builder, as NIR. Values that are used by more than the next
+/// instruction become locals named `_N`, with underscores added until they don't clash
+pub fn print_ir(m: &ir::Module) -> String {
+    let mut out = String::new();
+    if !m.strings.is_empty() {
+        out.push_str("--NIR--\n");
+        for c in &m.strings {
+            let _ = write!(out, "^{}=", m.name(c.name));
+            let _ = escape(&mut out, &c.value);
+            out.push_str(";\n");
+        }
+        out.push_str("--END--\n");
+    }
+    let mut first = m.strings.is_empty();
+    for s in &m.structs {
+        if !first {
+            out.push('\n');
+        }
+        first = false;
+        if s.packed {
+            out.push_str("packed ");
+        }
+        let _ = write!(out, "struct ${} {{", m.name(s.name));
+        for (i, field) in s.fields.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            if let Some(name) = field.name {
+                let _ = write!(out, "{}=", m.name(name));
+            }
+            let _ = write!(out, "{}", m.ty(&field.ty));
+        }
+        out.push_str("}\n");
+    }
+    for f in &m.functions {
+        if !first {
+            out.push('\n');
+        }
+        first = false;
+        let _ = write!(out, "fn ${}(", m.name(f.name));
+        if let Some(t) = &f.ret {
+            let _ = write!(out, "{}", m.ty(t));
+        }
+        out.push_str(") (");
+        for (i, p) in f.locals[..f.params].iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            let _ = write!(out, "{} {}", m.ty(&p.ty), m.name(p.name));
+        }
+        out.push_str(") {\n");
+
+        let locals = f.locals.iter().map(|l| m.name(l.name));
+        let named = f.blocks.iter().filter_map(|b| b.label).map(|l| m.name(l));
+        let label_prefix = fresh_prefix("_b", named);
+        let labels = f
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, b)| match b.label {
+                Some(l) => m.name(l).into(),
+                None => format!("{label_prefix}{i}"),
+            })
+            .collect();
+        let mut p = IrPrinter {
+            m,
+            f,
+            temps: BTreeMap::new(),
+            temp_prefix: fresh_prefix("_", locals),
+            labels,
+        };
+        let entry_target = f
+            .blocks
+            .iter()
+            .any(|b| b.term.successors().contains(&ir::BlockId(0)));
+        for (i, b) in f.blocks.iter().enumerate() {
+            if i > 0 || b.label.is_some() || entry_target {
+                let _ = writeln!(out, ".{}", p.labels[i]);
+            }
+            p.block(&mut out, b);
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+
+    const SRC: &str = "--NIR--
+^S=\"hi\";
+--END--
+struct $P {x=s32, y=u8, z=s64}
+struct $Q { u32, $P* }
+fn $fact(u64) (u64 n) {
+    &z = eq(u64) &n, !(u64)0
+    br(bool) &z, .base, .rec
+.base
+    ret(u64) !(u64)1
+.rec
+    &m = sub(u64) &n, !(u64)1
+    &r = call(u64) $fact (u64 &m)
+    &x = mul(u64) &n, &r
+    ret(u64) &x
+}
+fn $main() () {
+    &f = call(u64) $fact (u64 !(u64)20)
+    dbg(u64) &f
+    &p = stalloc $P times !(u64)2
+    &q = ptroffset($P) ptr &p !(u64)1
+    &off = cpy sptroffset $P, z
+    &b = sext(s64) s8 !(s8)0xF0
+    &c = itof(f64) s64 &b
+    &g = load(u8) ptr ^S
+    sys exit !(s32)0
+}
+";
+
+    /// Prints `src`, checking that the output parses to a module that prints the same
+    fn round_trip(src: &str, print: impl Fn(&Module, &str) -> String) -> String {
+        let once = print(&parse_module(src).unwrap(), src);
+        let twice = print(&parse_module(&once).unwrap(), &once);
+        assert_eq!(once, twice);
+        once
+    }
+
+    #[test]
+    fn round_trips() {
+        let out = round_trip(SRC, |m, _| print_module(m));
+        assert!(out.contains("&r = call(u64) $fact (u64 &m)"));
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let src = "; leading
+--NIR--
+// about A
+^A=\"x\"; ^B=\"y\";
+--END-- ; after end
+struct $S { u32, ; inside
+  u8 }
+fn $main() () { ; header
+    ; first
+    &x = add(u32) !(u32)1, !(u32)2 ; trailing
+.l  // label
+    dbg(u32) &x
+    ; before close
+} ; after close
+; end of file
+";
+        let out = round_trip(src, print_module_with_comments);
+        for c in [
+            "; leading",
+            "// about A",
+            "; after end",
+            "; inside",
+            "; header",
+            "; first",
+            "; trailing",
+            "// label",
+            "; before close",
+            "; after close",
+            "; end of file",
+        ] {
+            assert!(out.contains(c), "lost `{c}` in\n{out}");
+        }
+    }
+}
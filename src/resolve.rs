@@ -0,0 +1,418 @@
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    vec::Vec,
+};
+
+use crate::ast::{AstNode, Function, Item, Module, StructTable, Type};
+use crate::diagnostic::Diagnostic;
+use crate::verify::Cfg;
+use crate::Range;
+
+/// What a name refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Binding {
+    /// Index into the function's parameters, also where the parameter is assigned to
+    Param(usize),
+    /// A local, bound to the target of its first assignment
+    Local(Range),
+    /// Index into `Module::items`
+    Function(usize),
+    /// Index into `Module::items`
+    Struct(usize),
+    /// Index into `Module::header`
+    Header(usize),
+    /// Index into the host functions the module was resolved with
+    Host(usize),
+}
+
+/// Bindings of every `&local`, `$global` and `^constant` use in a module
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Keyed by the start of the use's span
+    bindings: BTreeMap<usize, Binding>,
+}
+
+impl Resolution {
+    pub fn get(&self, span: &Range) -> Option<&Binding> {
+        self.bindings.get(&span.start)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Binding)> {
+        self.bindings.iter().map(|(k, v)| (*k, v))
+    }
+}
+
+struct Resolver<'m, 'a, 'h> {
+    structs: StructTable<'m, 'a>,
+    globals: BTreeMap<&'a str, Binding>,
+    hosts: &'h [&'h str],
+    header: BTreeMap<&'a str, usize>,
+    res: Resolution,
+    diags: Vec<Diagnostic>,
+}
+
+/// Binds every name in `module` to its definition. Reports undefined and duplicate names,
+/// locals that may be used before they are assigned and assignments that shadow a parameter
+pub fn resolve_module(module: &Module) -> (Resolution, Vec<Diagnostic>) {
+    resolve_module_with(module, &[])
+}
+
+/// Like [`resolve_module`], `hosts` are the names of functions provided by the embedder. Functions
+/// of the module shadow them
+pub fn resolve_module_with(module: &Module, hosts: &[&str]) -> (Resolution, Vec<Diagnostic>) {
+    let mut r = Resolver {
+        structs: StructTable::new(module),
+        globals: BTreeMap::new(),
+        hosts,
+        header: BTreeMap::new(),
+        res: Resolution::default(),
+        diags: Vec::new(),
+    };
+    for (idx, c) in module.header.iter().enumerate() {
+        if let Some(first) = r.header.insert(c.name, idx) {
+            r.diags.push(
+                Diagnostic::error(
+                    format!("header constant `^{}` is defined more than once", c.name),
+                    c.span.clone(),
+                )
+                .with_secondary(module.header[first].span.clone(), "first defined here"),
+            );
+            r.header.insert(c.name, first);
+        }
+    }
+    for (idx, item) in module.items.iter().enumerate() {
+        let (name, binding) = match item {
+            Item::Function(f) => (f.name, Binding::Function(idx)),
+            Item::Struct(s) => (s.name, Binding::Struct(idx)),
+        };
+        if let Some(Binding::Function(first) | Binding::Struct(first)) = r.globals.get(name) {
+            r.diags.push(
+                Diagnostic::error(format!("`${name}` is defined more than once"), item.span())
+                    .with_secondary(module.items[*first].span(), "first defined here"),
+            );
+        } else {
+            r.globals.insert(name, binding);
+        }
+    }
+    for item in &module.items {
+        match item {
+            Item::Function(f) => r.function(f),
+            Item::Struct(s) => {
+                for field in &s.fields {
+                    r.check_type(&field.ty, &field.span);
+                }
+            }
+        }
+    }
+    let diags = r.diags.into_iter().map(|d| d.with_code("resolve"));
+    (r.res, diags.collect())
+}
+
+impl<'m, 'a, 'h> Resolver<'m, 'a, 'h> {
+    /// Struct references in a type must name a struct
+    fn check_type(&mut self, ty: &Type<'a>, span: &Range) {
+        let Some(name) = ty.struct_name() else {
+            return;
+        };
+        match self.globals.get(name) {
+            Some(Binding::Struct(_)) => (),
+            Some(_) => self.diags.push(Diagnostic::error(
+                format!("`${name}` is a function, not a struct"),
+                span.clone(),
+            )),
+            None => self.diags.push(Diagnostic::error(
+                format!("undefined struct `${name}`"),
+                span.clone(),
+            )),
+        }
+    }
+
+    fn function(&mut self, f: &Function<'a>) {
+        if let Some(ty) = &f.ret {
+            self.check_type(ty, &f.ret_span);
+        }
+        let mut params: BTreeMap<&str, usize> = BTreeMap::new();
+        for (idx, p) in f.params.iter().enumerate() {
+            self.check_type(&p.ty, &p.span);
+            if let Some(first) = params.get(p.name) {
+                self.diags.push(
+                    Diagnostic::error(
+                        format!("parameter `{}` is defined more than once", p.name),
+                        p.span.clone(),
+                    )
+                    .with_secondary(f.params[*first].span.clone(), "first defined here"),
+                );
+            } else {
+                params.insert(p.name, idx);
+            }
+        }
+
+        // first assignment of every local
+        let mut locals = BTreeMap::new();
+        for node in &f.body {
+            let AstNode::Equals(target, _, _) = node else {
+                continue;
+            };
+            match target.as_ref() {
+                AstNode::Local(name, r) => {
+                    if let (false, Some(p)) = (locals.contains_key(name), params.get(name)) {
+                        self.diags.push(
+                            Diagnostic::warning(
+                                format!("assignment to `&{name}` shadows the parameter"),
+                                r.clone(),
+                            )
+                            .with_secondary(f.params[*p].span.clone(), "parameter defined here"),
+                        );
+                    }
+                    locals.entry(*name).or_insert_with(|| r.clone());
+                }
+                other => self.diags.push(Diagnostic::error(
+                    "only locals can be assigned",
+                    other.span(),
+                )),
+            }
+        }
+
+        // locals assigned on every path into each block
+        let cfg = Cfg::new(&f.body);
+        let preds = cfg.predecessors();
+        let reachable = cfg.reachable();
+        let all: BTreeSet<&str> = params.keys().chain(locals.keys()).copied().collect();
+        let entry: BTreeSet<&str> = params.keys().copied().collect();
+        let defs = |b: usize| {
+            let b = &cfg.blocks[b];
+            f.body[b.start..b.end].iter().filter_map(|x| match x {
+                AstNode::Equals(t, _, _) => match t.as_ref() {
+                    AstNode::Local(name, _) => Some(*name),
+                    _ => None,
+                },
+                _ => None,
+            })
+        };
+        let mut ins = alloc::vec![all.clone(); cfg.blocks.len()];
+        ins[0] = entry;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in 1..cfg.blocks.len() {
+                if !reachable[b] {
+                    continue;
+                }
+                let mut new = all.clone();
+                for p in preds[b].iter().filter(|p| reachable[**p]) {
+                    let mut out = ins[*p].clone();
+                    out.extend(defs(*p));
+                    new = new.intersection(&out).copied().collect();
+                }
+                if new != ins[b] {
+                    ins[b] = new;
+                    changed = true;
+                }
+            }
+        }
+
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            let mut assigned = ins[b].clone();
+            for node in &f.body[block.start..block.end] {
+                match node {
+                    AstNode::Equals(target, op, _) => {
+                        self.uses(op, &assigned, &params, &locals);
+                        if let AstNode::Local(name, r) = target.as_ref() {
+                            assigned.insert(name);
+                            let binding = match params.get(name) {
+                                Some(idx) => Binding::Param(*idx),
+                                None => Binding::Local(locals[name].clone()),
+                            };
+                            self.res.bindings.insert(r.start, binding);
+                        }
+                    }
+                    _ => self.uses(node, &assigned, &params, &locals),
+                }
+            }
+        }
+    }
+
+    fn uses(
+        &mut self,
+        node: &AstNode<'a>,
+        assigned: &BTreeSet<&'a str>,
+        params: &BTreeMap<&'a str, usize>,
+        locals: &BTreeMap<&'a str, Range>,
+    ) {
+        for ty in node.types() {
+            self.check_type(ty, &node.span());
+        }
+        match node {
+            AstNode::Local(name, r) => {
+                // an assigned parameter stays the same variable
+                let binding = match (params.get(name), locals.get(name)) {
+                    _ if !assigned.contains(name) => None,
+                    (Some(idx), _) => Some(Binding::Param(*idx)),
+                    (None, Some(def)) => Some(Binding::Local(def.clone())),
+                    (None, None) => None,
+                };
+                match binding {
+                    Some(b) => {
+                        self.res.bindings.insert(r.start, b);
+                    }
+                    None if locals.contains_key(name) => self.diags.push(Diagnostic::error(
+                        format!("`&{name}` may be used before it is assigned"),
+                        r.clone(),
+                    )),
+                    None => self.diags.push(Diagnostic::error(
+                        format!("undefined local `&{name}`"),
+                        r.clone(),
+                    )),
+                }
+            }
+            AstNode::Global(name, r) => match self.globals.get(name) {
+                Some(b) => {
+                    self.res.bindings.insert(r.start, b.clone());
+                }
+                None => match self.hosts.iter().position(|h| h == name) {
+                    Some(idx) => {
+                        self.res.bindings.insert(r.start, Binding::Host(idx));
+                    }
+                    None => self.diags.push(Diagnostic::error(
+                        format!("undefined global `${name}`"),
+                        r.clone(),
+                    )),
+                },
+            },
+            AstNode::StrRef(name, r) => match self.header.get(name) {
+                Some(idx) => {
+                    self.res.bindings.insert(r.start, Binding::Header(*idx));
+                }
+                None => self.diags.push(Diagnostic::error(
+                    format!("undefined header constant `^{name}`"),
+                    r.clone(),
+                )),
+            },
+            AstNode::SPtrOffset(ty, path, r) => {
+                if self
+                    .structs
+                    .get(ty.struct_name().unwrap_or_default())
+                    .is_some()
+                {
+                    if let Err(e) = self.structs.resolve_path(ty, path) {
+                        self.diags
+                            .push(Diagnostic::error(format!("{e}"), r.clone()));
+                    }
+                }
+            }
+            _ => {
+                for op in node.operands() {
+                    self.uses(op, assigned, params, locals);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+    use alloc::string::String;
+
+    fn messages(src: &str) -> Vec<String> {
+        let (_, diags) = resolve_module(&parse_module(src).unwrap());
+        diags.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn undefined_names() {
+        let src = "--NIR--
+^A=\"a\";
+--END--
+fn $main() () {
+    &x = add(u32) &y, !(u32)1
+    &p = cpy ^B
+    &q = cpy $nope
+    &s = stalloc $S times !(u64)1
+    dbg(u32) &x
+}
+";
+        assert_eq!(
+            messages(src),
+            [
+                "undefined local `&y`",
+                "undefined header constant `^B`",
+                "undefined global `$nope`",
+                "undefined struct `$S`",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let src = "--NIR--
+^A=\"a\"; ^A=\"b\";
+--END--
+struct $f { u8 }
+fn $f() (u8 a, u16 a) {
+}
+";
+        assert_eq!(
+            messages(src),
+            [
+                "header constant `^A` is defined more than once",
+                "`$f` is defined more than once",
+                "parameter `a` is defined more than once",
+            ]
+        );
+    }
+
+    #[test]
+    fn used_before_assigned() {
+        let src = "fn $main() (bool c) {
+    br(bool) &c, .a, .b
+.a
+    &x = cpy !(u32)1
+.b
+    dbg(u32) &x
+}
+";
+        assert_eq!(messages(src), ["`&x` may be used before it is assigned"]);
+    }
+
+    #[test]
+    fn assigned_parameters() {
+        let src = "fn $f(u32) (u32 n) {
+    &m = add(u32) &n, !(u32)1
+    &n = cpy &m
+    ret(u32) &n
+}
+";
+        let module = parse_module(src).unwrap();
+        let (res, diags) = resolve_module(&module);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "assignment to `&n` shadows the parameter");
+        let uses: Vec<_> = src
+            .match_indices("&n")
+            .map(|(at, _)| res.get(&(at..at + 2)))
+            .collect();
+        assert_eq!(uses, [Some(&Binding::Param(0)); 3]);
+    }
+
+    #[test]
+    fn hosts_and_shadowing() {
+        let src = "fn $print() () {
+}
+fn $main() () {
+    &a = call() $print ()
+    &b = call() $log ()
+}
+";
+        let module = parse_module(src).unwrap();
+        let (res, diags) = resolve_module_with(&module, &["log", "print"]);
+        assert!(diags.is_empty());
+        let at = |s: &str| {
+            let start = src.find(s).unwrap();
+            res.get(&(start..start + s.len())).cloned()
+        };
+        assert_eq!(at("$print ("), Some(Binding::Function(0)));
+        assert_eq!(at("$log"), Some(Binding::Host(0)));
+    }
+}
@@ -0,0 +1,90 @@
+/// The outside world as the interpreter's `sys` instructions see it. Errors are negative,
+/// `-errno` where there is one
+pub trait System {
+    fn write(&mut self, fd: i32, bytes: &[u8]) -> i64;
+    fn read(&mut self, fd: i32, buf: &mut [u8]) -> i64;
+    /// Nanoseconds since an unspecified point in time, never decreases
+    fn clock(&mut self) -> u64;
+}
+
+pub const EBADF: i64 = 9;
+pub const EIO: i64 = 5;
+pub const ENOSYS: i64 = 38;
+
+/// A program without access to anything, every read and write fails and the clock stands still
+pub struct Isolated;
+
+impl System for Isolated {
+    fn write(&mut self, _: i32, _: &[u8]) -> i64 {
+        -ENOSYS
+    }
+
+    fn read(&mut self, _: i32, _: &mut [u8]) -> i64 {
+        -ENOSYS
+    }
+
+    fn clock(&mut self) -> u64 {
+        0
+    }
+}
+
+/// The standard streams and monotonic clock of the running process. Other file descriptors
+/// aren't accessible
+#[cfg(feature = "std")]
+pub struct Process {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl Process {
+    pub fn new() -> Self {
+        Process {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Process {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+fn result(r: std::io::Result<usize>) -> i64 {
+    match r {
+        Ok(n) => n as i64,
+        Err(e) => -e.raw_os_error().map_or(EIO, i64::from),
+    }
+}
+
+#[cfg(feature = "std")]
+impl System for Process {
+    fn write(&mut self, fd: i32, bytes: &[u8]) -> i64 {
+        use std::io::Write;
+        // unbuffered like the write syscall, `dbg` output and exit codes come after it
+        fn write(mut w: impl Write, bytes: &[u8]) -> std::io::Result<usize> {
+            let n = w.write(bytes)?;
+            w.flush()?;
+            Ok(n)
+        }
+        result(match fd {
+            1 => write(std::io::stdout().lock(), bytes),
+            2 => write(std::io::stderr().lock(), bytes),
+            _ => return -EBADF,
+        })
+    }
+
+    fn read(&mut self, fd: i32, buf: &mut [u8]) -> i64 {
+        use std::io::Read;
+        match fd {
+            0 => result(std::io::stdin().lock().read(buf)),
+            _ => -EBADF,
+        }
+    }
+
+    fn clock(&mut self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
@@ -0,0 +1,485 @@
+use alloc::{collections::BTreeMap, format, vec::Vec};
+
+use crate::ast::{AstNode, BinOp, Function, Item, Module, Type, TypeSize, UnOp};
+use crate::diagnostic::Diagnostic;
+use crate::grammar::parse_number;
+
+/// Inferred types of the locals of one function, parameters included
+#[derive(Debug, Default)]
+pub struct FunctionTypes<'a> {
+    pub locals: BTreeMap<&'a str, Type<'a>>,
+}
+
+#[derive(Debug, Default)]
+pub struct TypeInfo<'a> {
+    pub functions: BTreeMap<&'a str, FunctionTypes<'a>>,
+}
+
+impl<'a> TypeInfo<'a> {
+    pub fn local(&self, function: &str, name: &str) -> Option<&Type<'a>> {
+        self.functions.get(function)?.locals.get(name)
+    }
+}
+
+/// Whether the constant `lit` can be a value of `ty`. Decimal constants of signed types must be
+/// positive, hex and binary constants may set every bit
+pub fn const_fits(ty: &Type, lit: &str) -> bool {
+    let Some(v) = parse_number(lit) else {
+        return false;
+    };
+    let decimal = !lit.starts_with("0x") && !lit.starts_with("0b");
+    match ty {
+        Type::U(s) => s.bits() == 64 || v < 1 << s.bits(),
+        Type::I(s) if decimal => v < 1 << (s.bits() - 1),
+        Type::I(s) => s.bits() == 64 || v < 1 << s.bits(),
+        Type::F(_) => true,
+        Type::Bool => v <= 1,
+        Type::Ptr(_) => v == 0,
+        Type::Ref(_) => false,
+    }
+}
+
+/// Whether `op` is defined for operands of type `ty`
+pub fn binop_accepts(op: BinOp, ty: &Type) -> bool {
+    let float = matches!(ty, Type::F(_));
+    match op {
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => ty.is_int() || float,
+        BinOp::And | BinOp::Or | BinOp::Xor => ty.is_int() || *ty == Type::Bool,
+        BinOp::Shl | BinOp::Shr => ty.is_int(),
+        BinOp::Eq | BinOp::Ne => ty.is_int() || float || matches!(ty, Type::Bool | Type::Ptr(_)),
+        BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => ty.is_int() || float,
+    }
+}
+
+pub fn unop_accepts(op: UnOp, ty: &Type) -> bool {
+    match op {
+        UnOp::Neg => ty.is_int() || matches!(ty, Type::F(_)),
+        UnOp::Not => ty.is_int() || *ty == Type::Bool,
+    }
+}
+
+struct Checker<'m, 'a> {
+    functions: BTreeMap<&'a str, &'m Function<'a>>,
+    diags: Vec<Diagnostic>,
+}
+
+/// Infers the type of every local and checks every instruction against its type annotation,
+/// its operands and, for calls, the callee's signature
+pub fn check_module<'a>(module: &Module<'a>) -> (TypeInfo<'a>, Vec<Diagnostic>) {
+    let mut c = Checker {
+        functions: BTreeMap::new(),
+        diags: Vec::new(),
+    };
+    for item in &module.items {
+        if let Item::Function(f) = item {
+            c.functions.entry(f.name).or_insert(f);
+        }
+    }
+    let mut info = TypeInfo::default();
+    for item in &module.items {
+        if let Item::Function(f) = item {
+            let locals = c.function(f);
+            info.functions
+                .entry(f.name)
+                .or_insert(FunctionTypes { locals });
+        }
+    }
+    let diags = c.diags.into_iter().map(|d| d.with_code("type"));
+    (info, diags.collect())
+}
+
+/// Type of an atom, `None` if it depends on a local whose type isn't known
+pub fn atom_ty<'a>(node: &AstNode<'a>, locals: &BTreeMap<&'a str, Type<'a>>) -> Option<Type<'a>> {
+    match node {
+        AstNode::Local(name, _) => locals.get(name).cloned(),
+        AstNode::StrRef(..) => Some(Type::U(TypeSize::_8).ptr()),
+        AstNode::Const(t, _, _) => Some(t.clone()),
+        AstNode::CPtrOffset(..) | AstNode::SPtrOffset(..) => Some(Type::U(TypeSize::_64)),
+        _ => None,
+    }
+}
+
+/// Type of the value an instruction produces, `None` if it has none or it can't be known yet
+pub fn result_ty<'a>(node: &AstNode<'a>, locals: &BTreeMap<&'a str, Type<'a>>) -> Option<Type<'a>> {
+    if let Some((op, t, _, _)) = node.binop() {
+        return Some(if op.is_comparison() {
+            Type::Bool
+        } else {
+            t.clone()
+        });
+    }
+    match node {
+        AstNode::Cpy(a, _) => atom_ty(a, locals),
+        AstNode::Neg(t, _, _) | AstNode::Not(t, _, _) | AstNode::Load(t, _, _) => Some(t.clone()),
+        AstNode::Cast(_, to, _, _, _) => Some(to.clone()),
+        AstNode::Call(t, _, _, _) => t.clone(),
+        AstNode::Sys(op, _, _) => op.ret(),
+        AstNode::Stalloc(t, _, _)
+        | AstNode::Halloc(t, _, _)
+        | AstNode::Hrealloc(t, _, _, _)
+        | AstNode::Ptroffset(t, _, _, _) => Some(t.clone().ptr()),
+        _ => None,
+    }
+}
+
+impl<'m, 'a> Checker<'m, 'a> {
+    fn function(&mut self, f: &Function<'a>) -> BTreeMap<&'a str, Type<'a>> {
+        let mut locals = BTreeMap::new();
+        for p in &f.params {
+            locals.entry(p.name).or_insert_with(|| p.ty.clone());
+        }
+        // infer until nothing changes, a `cpy` may come before the assignment it copies from
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for node in &f.body {
+                if let AstNode::Equals(target, op, _) = node {
+                    if let AstNode::Local(name, _) = target.as_ref() {
+                        if !locals.contains_key(name) {
+                            if let Some(t) = result_ty(op, &locals) {
+                                locals.insert(name, t);
+                                changed = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for node in &f.body {
+            match node {
+                AstNode::Equals(target, op, _) => {
+                    self.instruction(f, op, &locals);
+                    let AstNode::Local(name, r) = target.as_ref() else {
+                        continue;
+                    };
+                    if let AstNode::Call(None, ..) = op.as_ref() {
+                        self.diags.push(Diagnostic::error(
+                            "calling a void function doesn't produce a value",
+                            op.span(),
+                        ));
+                        continue;
+                    }
+                    if let AstNode::Sys(sys, _, _) = op.as_ref() {
+                        if sys.ret().is_none() {
+                            self.diags.push(Diagnostic::error(
+                                format!("`sys {}` doesn't produce a value", sys.name()),
+                                op.span(),
+                            ));
+                            continue;
+                        }
+                    }
+                    match (locals.get(name), result_ty(op, &locals)) {
+                        (Some(t), Some(p)) if *t != p => self.diags.push(Diagnostic::error(
+                            format!("`&{name}` has type {t}, but is assigned a {p}"),
+                            op.span(),
+                        )),
+                        (None, _) => self.diags.push(Diagnostic::error(
+                            format!("can't infer the type of `&{name}`"),
+                            r.clone(),
+                        )),
+                        _ => (),
+                    }
+                }
+                _ => self.instruction(f, node, &locals),
+            }
+        }
+        locals
+    }
+
+    /// Checks that an operand has type `expected`
+    fn expect(
+        &mut self,
+        node: &AstNode<'a>,
+        expected: &Type<'a>,
+        locals: &BTreeMap<&'a str, Type<'a>>,
+    ) {
+        self.atom(node);
+        if let Some(t) = atom_ty(node, locals) {
+            if t != *expected {
+                self.diags.push(Diagnostic::error(
+                    format!("expected {expected}, found {t}"),
+                    node.span(),
+                ));
+            }
+        }
+    }
+
+    /// Checks the element count of an allocation
+    fn count(&mut self, n: &AstNode<'a>, locals: &BTreeMap<&'a str, Type<'a>>) {
+        self.atom(n);
+        if let Some(t) = atom_ty(n, locals) {
+            if !t.is_int() {
+                self.diags.push(Diagnostic::error(
+                    format!("element count must be an integer, found {t}"),
+                    n.span(),
+                ));
+            }
+        }
+    }
+
+    /// Checks for atoms that need no context
+    fn atom(&mut self, node: &AstNode<'a>) {
+        match node {
+            AstNode::Const(t, lit, r) if !const_fits(t, lit) => self.diags.push(Diagnostic::error(
+                format!("constant {lit} doesn't fit in {t}"),
+                r.clone(),
+            )),
+            AstNode::Global(name, r) => self.diags.push(Diagnostic::error(
+                format!("`${name}` can only be called"),
+                r.clone(),
+            )),
+            _ => (),
+        }
+    }
+
+    fn instruction(
+        &mut self,
+        f: &Function<'a>,
+        node: &AstNode<'a>,
+        locals: &BTreeMap<&'a str, Type<'a>>,
+    ) {
+        let span = node.span();
+        if let Some((op, t, a, b)) = node.binop() {
+            if !binop_accepts(op, t) {
+                self.diags.push(Diagnostic::error(
+                    format!("`{}` is not defined for {t}", op.name()),
+                    span,
+                ));
+            }
+            self.expect(a, t, locals);
+            self.expect(b, t, locals);
+            return;
+        }
+        if let Some((op, t, a)) = node.unop() {
+            if !unop_accepts(op, t) {
+                self.diags.push(Diagnostic::error(
+                    format!("`{}` is not defined for {t}", op.name()),
+                    span,
+                ));
+            }
+            self.expect(a, t, locals);
+            return;
+        }
+        match node {
+            AstNode::Cpy(a, _) => self.atom(a),
+            AstNode::Cast(_, _, from, a, _) => self.expect(a, from, locals),
+            AstNode::Call(t, target, args, _) => {
+                let callee = match target.as_ref() {
+                    AstNode::Global(name, _) => self.functions.get(name).copied(),
+                    _ => None,
+                };
+                let Some(callee) = callee else {
+                    if !matches!(target.as_ref(), AstNode::Global(..)) {
+                        self.diags.push(Diagnostic::error(
+                            "only `$functions` can be called",
+                            target.span(),
+                        ));
+                    }
+                    // an undefined global is reported by the resolver
+                    return;
+                };
+                if *t != callee.ret {
+                    let show = |t: &Option<Type>| match t {
+                        Some(t) => format!("{t}"),
+                        None => "()".into(),
+                    };
+                    self.diags.push(
+                        Diagnostic::error(
+                            format!(
+                                "`${}` returns {}, but is called as returning {}",
+                                callee.name,
+                                show(&callee.ret),
+                                show(t)
+                            ),
+                            span.clone(),
+                        )
+                        .with_secondary(callee.span.clone(), "defined here"),
+                    );
+                }
+                if args.len() != callee.params.len() {
+                    self.diags.push(
+                        Diagnostic::error(
+                            format!(
+                                "`${}` takes {} arguments, but {} were given",
+                                callee.name,
+                                callee.params.len(),
+                                args.len()
+                            ),
+                            span,
+                        )
+                        .with_secondary(callee.span.clone(), "defined here"),
+                    );
+                }
+                for ((t, a), p) in args.iter().zip(&callee.params) {
+                    if *t != p.ty {
+                        self.diags.push(
+                            Diagnostic::error(
+                                format!("parameter `{}` has type {}, not {t}", p.name, p.ty),
+                                a.span(),
+                            )
+                            .with_secondary(p.span.clone(), "parameter defined here"),
+                        );
+                    }
+                    self.expect(a, t, locals);
+                }
+            }
+            AstNode::Ret(t, a, _) => {
+                if let Some(ret) = &f.ret {
+                    if t != ret {
+                        self.diags.push(Diagnostic::error(
+                            format!("`${}` returns {ret}, not {t}", f.name),
+                            span,
+                        ));
+                    }
+                }
+                self.expect(a, t, locals);
+            }
+            AstNode::Hrealloc(t, p, n, _) => {
+                self.expect(p, &t.clone().ptr(), locals);
+                self.count(n, locals);
+            }
+            AstNode::Hfree(p, _) => {
+                self.atom(p);
+                if let Some(t) = atom_ty(p, locals) {
+                    if !matches!(t, Type::Ptr(_)) {
+                        self.diags.push(Diagnostic::error(
+                            format!("`hfree` needs a pointer, found {t}"),
+                            p.span(),
+                        ));
+                    }
+                }
+            }
+            AstNode::Stalloc(_, n, _) | AstNode::Halloc(_, n, _) => self.count(n, locals),
+            AstNode::Ptroffset(t, p, i, _) => {
+                self.expect(p, &t.clone().ptr(), locals);
+                self.atom(i);
+                if let Some(it) = atom_ty(i, locals) {
+                    if !it.is_int() {
+                        self.diags.push(Diagnostic::error(
+                            format!("offset must be an integer, found {it}"),
+                            i.span(),
+                        ));
+                    }
+                }
+            }
+            AstNode::Load(t, p, _) => self.expect(p, &t.clone().ptr(), locals),
+            AstNode::Store(t, p, v, _) => {
+                self.expect(p, &t.clone().ptr(), locals);
+                self.expect(v, t, locals);
+            }
+            AstNode::Dbg(t, a, _) => self.expect(a, t, locals),
+            AstNode::Sys(op, args, _) => {
+                let params = op.params();
+                if args.len() != params.len() {
+                    self.diags.push(Diagnostic::error(
+                        format!(
+                            "`sys {}` takes {} operands, but {} were given",
+                            op.name(),
+                            params.len(),
+                            args.len()
+                        ),
+                        span,
+                    ));
+                }
+                for (a, t) in args.iter().zip(&params) {
+                    self.expect(a, t, locals);
+                }
+            }
+            AstNode::Br(t, c, _, _, _) => {
+                if !t.is_int() && *t != Type::Bool {
+                    self.diags.push(Diagnostic::error(
+                        format!("`br` needs a bool or integer, not {t}"),
+                        span,
+                    ));
+                }
+                self.expect(c, t, locals);
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+    use alloc::string::{String, ToString};
+
+    fn messages(src: &str) -> Vec<String> {
+        let (_, diags) = check_module(&parse_module(src).unwrap());
+        diags.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn infers_locals() {
+        let src = "struct $P { x=u8, y=u32 }
+fn $main() () {
+    &b = cpy &a
+    &a = add(u32) !(u32)1, !(u32)2
+    &c = lt(u32) &a, &b
+    &p = stalloc $P times !(u64)1
+    &o = cpy sptroffset $P, y
+}
+";
+        let module = parse_module(src).unwrap();
+        let (info, diags) = check_module(&module);
+        assert!(diags.is_empty());
+        let ty = |name| info.local("main", name).map(|t| t.to_string());
+        assert_eq!(ty("b").as_deref(), Some("u32"));
+        assert_eq!(ty("c").as_deref(), Some("bool"));
+        assert_eq!(ty("p").as_deref(), Some("$P*"));
+        assert_eq!(ty("o").as_deref(), Some("u64"));
+    }
+
+    #[test]
+    fn mismatches() {
+        let src = "fn $f(u32) (u32 a) {
+    ret(u64) !(u64)1
+}
+fn $main() () {
+    &a = add(u32) !(u32)1, !(u64)2
+    &a = cpy !(u8)1
+    &b = shl(f32) !(f32)1, !(f32)1
+    &c = call(u64) $f (u64 !(u64)1, u32 &a)
+    &d = cpy !(u8)256
+    &y = cpy &y
+}
+";
+        assert_eq!(
+            messages(src),
+            [
+                "`$f` returns u32, not u64",
+                "expected u32, found u64",
+                "`&a` has type u32, but is assigned a u8",
+                "`shl` is not defined for f32",
+                "`$f` returns u32, but is called as returning u64",
+                "`$f` takes 1 arguments, but 2 were given",
+                "parameter `a` has type u32, not u64",
+                "constant 256 doesn't fit in u8",
+                "can't infer the type of `&y`",
+            ]
+        );
+    }
+
+    #[test]
+    fn struct_pointers() {
+        let src = "struct $P { x=u8, y=u32 }
+fn $main() () {
+    &p = stalloc $P times !(u64)1
+    &o = cpy sptroffset $P, y
+    &i = ptrtoint(u64) $P* &p
+    &a = add(u64) &i, &o
+    &y = inttoptr(u32*) u64 &a
+    store(u32) ptr &y, !(u32)1
+    store(u32) ptr &p, !(u32)1
+    &q = ptroffset($P) ptr &p !(u32)1
+    &r = ptroffset(u8) ptr &p &o
+}
+";
+        assert_eq!(
+            messages(src),
+            ["expected u32*, found $P*", "expected u8*, found $P*",]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use alloc::{collections::BTreeMap, format, vec, vec::Vec};
+
+use crate::ast::{AstNode, Function, Item, Module};
+use crate::diagnostic::Diagnostic;
+
+/// A run of instructions `body[start..end]` that is only entered at the top
+pub(crate) struct Block<'a> {
+    pub label: Option<&'a str>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Control flow graph of a function body. Jumps to undefined labels have no edge
+pub(crate) struct Cfg<'a> {
+    pub blocks: Vec<Block<'a>>,
+    /// First block with each label
+    pub labels: BTreeMap<&'a str, usize>,
+    pub successors: Vec<Vec<usize>>,
+}
+
+impl<'a> Cfg<'a> {
+    pub fn new(body: &[AstNode<'a>]) -> Self {
+        let blocks = blocks(body);
+        let mut labels = BTreeMap::new();
+        for (idx, b) in blocks.iter().enumerate() {
+            if let Some(l) = b.label {
+                labels.entry(l).or_insert(idx);
+            }
+        }
+        let successors = blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, b)| match b.end.checked_sub(1).map(|x| &body[x]) {
+                Some(node) if node.is_terminator() => node
+                    .targets()
+                    .into_iter()
+                    .filter_map(|t| labels.get(t).copied())
+                    .collect(),
+                _ if idx + 1 < blocks.len() => vec![idx + 1],
+                _ => Vec::new(),
+            })
+            .collect();
+        Cfg {
+            blocks,
+            labels,
+            successors,
+        }
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (b, succ) in self.successors.iter().enumerate() {
+            for s in succ {
+                preds[*s].push(b);
+            }
+        }
+        preds
+    }
+
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut todo = vec![0];
+        while let Some(b) = todo.pop() {
+            if !reachable[b] {
+                reachable[b] = true;
+                todo.extend(&self.successors[b]);
+            }
+        }
+        reachable
+    }
+}
+
+/// Splits a function body at labels and terminators
+fn blocks<'a>(body: &[AstNode<'a>]) -> Vec<Block<'a>> {
+    let mut blocks = vec![Block {
+        label: None,
+        start: 0,
+        end: 0,
+    }];
+    for (i, node) in body.iter().enumerate() {
+        let cur = blocks.last_mut().unwrap();
+        if let AstNode::Label(name, _) = node {
+            if cur.label.is_none() && cur.start == cur.end {
+                // nothing before the label in this block, e.g. the function starts with it
+                cur.label = Some(name);
+            } else {
+                blocks.push(Block {
+                    label: Some(name),
+                    start: i,
+                    end: i,
+                });
+            }
+            blocks.last_mut().unwrap().end = i + 1;
+            continue;
+        }
+        cur.end = i + 1;
+        if node.is_terminator() && i + 1 < body.len() {
+            blocks.push(Block {
+                label: None,
+                start: i + 1,
+                end: i + 1,
+            });
+        }
+    }
+    blocks
+}
+
+/// Checks control flow of every function: labels are defined once, jumps go to
+/// defined labels, non-void functions can't fall off their end and every block is reachable.
+/// Also checks that conversions are between legal pairs of types and that no statement is an
+/// [`AstNode::Err`] from a partial parse
+pub fn verify_module(module: &Module) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    for item in &module.items {
+        if let Item::Function(f) = item {
+            verify_function(f, &mut diags);
+        }
+    }
+    diags.into_iter().map(|d| d.with_code("verify")).collect()
+}
+
+fn verify_function(f: &Function, diags: &mut Vec<Diagnostic>) {
+    let cfg = Cfg::new(&f.body);
+    for (idx, b) in cfg.blocks.iter().enumerate() {
+        if let Some(l) = b.label {
+            let first = cfg.labels[l];
+            if first != idx {
+                diags.push(
+                    Diagnostic::error(
+                        format!("label `.{l}` is defined more than once"),
+                        f.body[b.start].span(),
+                    )
+                    .with_secondary(f.body[cfg.blocks[first].start].span(), "first defined here"),
+                );
+            }
+        }
+        let last = b.end.checked_sub(1).map(|x| &f.body[x]);
+        match last {
+            Some(node) if node.is_terminator() => {
+                for target in node.targets() {
+                    if !cfg.labels.contains_key(target) {
+                        diags.push(Diagnostic::error(
+                            format!("jump to undefined label `.{target}`"),
+                            node.span(),
+                        ));
+                    }
+                }
+            }
+            _ if idx + 1 < cfg.blocks.len() => (),
+            _ => {
+                if f.ret.is_some() {
+                    diags.push(Diagnostic::error(
+                        format!("function `${}` can reach its end without `ret`", f.name),
+                        last.map_or(f.span.clone(), |x| x.span()),
+                    ));
+                }
+            }
+        }
+    }
+
+    for node in &f.body {
+        let op = match node {
+            AstNode::Equals(_, op, _) => op,
+            _ => node,
+        };
+        if let AstNode::Cast(c, to, from, _, r) = op {
+            if !c.is_legal(from, to) {
+                diags.push(Diagnostic::error(
+                    format!("`{}` can't convert {from} to {to}", c.name()),
+                    r.clone(),
+                ));
+            }
+        }
+        match (node, &f.ret) {
+            // Left by `parse_module_partial`, nothing can be done with it
+            (AstNode::Err(_, r), _) => diags.push(Diagnostic::error(
+                "statement that doesn't parse can't be checked or lowered",
+                r.clone(),
+            )),
+            (AstNode::RetVoid(r), Some(ty)) => diags.push(Diagnostic::error(
+                format!("`ret` needs a value of type {ty} in a non-void function"),
+                r.clone(),
+            )),
+            (AstNode::Ret(_, _, r), None) => diags.push(Diagnostic::error(
+                "void function can only use `ret` without a type and operand",
+                r.clone(),
+            )),
+            _ => (),
+        }
+    }
+
+    for (b, _) in cfg
+        .blocks
+        .iter()
+        .zip(cfg.reachable())
+        .filter(|(b, r)| !r && b.start != b.end)
+    {
+        let span = f.body[b.start].span().start..f.body[b.end - 1].span().end;
+        diags.push(Diagnostic::warning("unreachable code", span));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_module;
+    use alloc::{format, string::String};
+
+    /// Messages for `&x = <cast> !(<from>)1`
+    fn cast(cast: &str, from: &str) -> Vec<String> {
+        let src = format!("fn $main() () {{\n    &x = {cast} !({from})1\n}}\n");
+        let diags = verify_module(&parse_module(&src).unwrap());
+        diags.into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn legal_casts() {
+        assert!(cast("trunc(u32) u64", "u64").is_empty());
+        assert!(cast("trunc(s8) s16", "s16").is_empty());
+        assert!(cast("trunc(bool) u8", "u8").is_empty());
+        assert!(cast("zext(u64) bool", "bool").is_empty());
+        assert!(cast("bitcast(f32) u32", "u32").is_empty());
+    }
+
+    #[test]
+    fn illegal_casts() {
+        assert_eq!(
+            cast("trunc(s32) u32", "u32"),
+            ["`trunc` can't convert u32 to s32"]
+        );
+        assert_eq!(
+            cast("trunc(u64) u32", "u32"),
+            ["`trunc` can't convert u32 to u64"]
+        );
+        assert_eq!(
+            cast("zext(u32) u32", "u32"),
+            ["`zext` can't convert u32 to u32"]
+        );
+        assert_eq!(
+            cast("sext(s64) bool", "bool"),
+            ["`sext` can't convert bool to s64"]
+        );
+        assert_eq!(
+            cast("bitcast(f64) u32", "u32"),
+            ["`bitcast` can't convert u32 to f64"]
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let src = "fn $main() () {
+    jmp .a
+.a
+    jmp .b
+.a
+    ret
+}
+";
+        let diags = verify_module(&parse_module(src).unwrap());
+        let messages: Vec<String> = diags.into_iter().map(|d| d.message).collect();
+        assert_eq!(
+            messages,
+            [
+                "jump to undefined label `.b`",
+                "label `.a` is defined more than once",
+                "unreachable code",
+            ]
+        );
+    }
+}